@@ -0,0 +1,55 @@
+//! Writes a synthetic little endian ARM binary, the input of the sample output in the README:
+//! 800 functions of 10 to 60 instructions that end in `bx lr`, where about 8% of the instructions are `bl` to the
+//! start of a random function and the rest are common data processing, load/store and branch instructions with
+//! random operands.
+//!
+//! `cargo run --example synthetic_arm -- arm.bin`
+
+const BX_LR: u32 = 0xe12fff1e;
+const BL: u32 = 0xeb000000;
+// Condition and opcode bytes of the other instructions
+const OPCODES: [u32; 7] = [0xe5, 0xe2, 0xe1, 0xe3, 0x1a, 0x0a, 0xe8];
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "arm.bin".to_string());
+
+    // xorshift, the binary is the same on every run
+    let mut seed = 0x2545f491u32;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+
+    let sizes: Vec<u32> = (0..800).map(|_| 10 + random() % 51).collect();
+    let starts: Vec<u32> = sizes
+        .iter()
+        .scan(0, |start, size| {
+            let function_start = *start;
+            *start += size;
+            Some(function_start)
+        })
+        .collect();
+
+    let mut words: Vec<u32> = Vec::new();
+    for size in sizes {
+        for _ in 1..size {
+            let index = words.len() as u32;
+            let word = if random() % 100 < 8 {
+                // In words, relative to the PC which reads two instructions ahead
+                let target = starts[random() as usize % starts.len()];
+                BL | (target.wrapping_sub(index + 2) & 0xffffff)
+            } else {
+                (OPCODES[random() as usize % OPCODES.len()] << 24) | (random() & 0xffffff)
+            };
+            words.push(word);
+        }
+        words.push(BX_LR);
+    }
+
+    let binary: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    std::fs::write(&path, binary).expect("could not write the binary");
+}
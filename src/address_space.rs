@@ -0,0 +1,238 @@
+use crate::prelude::*;
+
+/// Maps between offsets in the slice of a hypothesis (see `iter_potential_instruction_configuration`), offsets in
+/// the binary and virtual addresses. Without a memory map the analysed range of the binary is loaded at
+/// `pc_offset`, scaled to bytes for word addressing. Bit packed instructions are addressed in instruction units,
+/// thus their slice offsets and addresses count instructions.
+#[derive(Clone, Copy)]
+pub struct AddressSpace<'a> {
+    memory_map: Option<&'a MemoryMap>,
+    // Offset in the binary (the file, or the image of the memory map) of the first byte of the slice
+    slice_start: usize,
+    // Number of addressable units in the slice, see `Config::address_space_len`
+    slice_len: usize,
+    // Address of the first unit of the slice, unused with a memory map
+    base: u64,
+    // Instruction length of bit packed instructions
+    packed_instr_len: Option<u64>,
+}
+
+impl<'a> AddressSpace<'a> {
+    pub fn new(
+        binary_slice: &[u8],
+        config: &'a Config,
+        instruction_configuration: &InstructionConfiguration,
+    ) -> AddressSpace<'a> {
+        let &InstructionConfiguration {
+            byte_offset,
+            ref addressing_mode,
+            ..
+        } = instruction_configuration;
+        let [start, _] = config.file_offset.unwrap_or([0, 0]);
+
+        // pc_offset is in the same unit as the operand
+        let address_scale = match addressing_mode {
+            AddressingMode::AbsoluteWord | AddressingMode::RelativeWord => {
+                config.word_addr_len() as u64
+            }
+            _ => 1,
+        };
+        let packed_instr_len =
+            (config.bit_packed && config.length_rule.is_none()).then_some(config.instr_len);
+        // A bit packed slice starts within the first instruction rather than at an address of its own
+        let base = match packed_instr_len {
+            Some(_) => config.pc_offset(),
+            None => config.pc_offset() * address_scale + byte_offset as u64,
+        };
+
+        AddressSpace {
+            memory_map: config.memory_map.as_ref(),
+            slice_start: start + byte_offset,
+            slice_len: config.address_space_len(binary_slice),
+            base,
+            packed_instr_len,
+        }
+    }
+
+    /// Address of the unit at the offset in the slice, None outside the slice and for the padding between the
+    /// regions of a memory map
+    pub fn address(&self, slice_offset: usize) -> Option<u64> {
+        if slice_offset >= self.slice_len {
+            return None;
+        }
+        match self.memory_map {
+            Some(memory_map) => memory_map.address(self.slice_start + slice_offset),
+            None => Some(self.base + slice_offset as u64),
+        }
+    }
+
+    /// Lowest address of the slice, that of the first code region for a memory map
+    pub fn start(&self) -> u64 {
+        match self.memory_map {
+            Some(memory_map) => memory_map
+                .code_regions()
+                .next()
+                .map_or(0, |region| region.address),
+            None => self.base,
+        }
+    }
+
+    /// Ranges of slice offsets holding code, the code regions of a memory map or else the whole slice
+    pub fn code_ranges(&self) -> Vec<[usize; 2]> {
+        match self.memory_map {
+            Some(memory_map) => memory_map
+                .code_regions()
+                .filter_map(|region| {
+                    let start = memory_map.image_offset(region.address)?;
+                    let range = [start, start + region.len()].map(|image_offset| {
+                        image_offset
+                            .saturating_sub(self.slice_start)
+                            .min(self.slice_len)
+                    });
+                    (range[0] < range[1]).then_some(range)
+                })
+                .collect(),
+            None => vec![[0, self.slice_len]],
+        }
+    }
+
+    /// Offset in the slice of the unit at the address, None if it is not in the slice
+    pub fn slice_offset(&self, address: u64) -> Option<usize> {
+        let slice_offset = match self.memory_map {
+            Some(memory_map) => memory_map
+                .image_offset(address)?
+                .checked_sub(self.slice_start)?,
+            None => usize::try_from(address.checked_sub(self.base)?).ok()?,
+        };
+        (slice_offset < self.slice_len).then_some(slice_offset)
+    }
+
+    /// Offset in the file of the unit at the offset in the slice, the byte holding the first bit of bit packed
+    /// instructions. Decoded HEX and S-record files have no file offsets, their image offset is returned instead.
+    pub fn file_offset(&self, slice_offset: usize) -> Option<usize> {
+        if slice_offset >= self.slice_len {
+            return None;
+        }
+        match (self.memory_map, self.packed_instr_len) {
+            (Some(memory_map), _) => memory_map.file_offset(self.slice_start + slice_offset),
+            (None, Some(instr_len)) => {
+                Some(self.slice_start + (slice_offset as u64 * instr_len / BYTE_SIZE) as usize)
+            }
+            (None, None) => Some(self.slice_start + slice_offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iter_instructions::instruction_slice;
+
+    fn instruction_configuration(
+        byte_offset: usize,
+        addressing_mode: AddressingMode,
+    ) -> InstructionConfiguration {
+        InstructionConfiguration {
+            byte_offset,
+            endiannes: Endiannes::Big,
+            addressing_mode,
+            pc_bias: 0,
+            left_shift_call_operand: 0,
+        }
+    }
+
+    #[test]
+    fn slice_is_loaded_at_the_pc_offset() {
+        let binary = [0; 0x50];
+        let config = Config {
+            file_offset: Some([0x10, 0x50]),
+            pc_offset: Some(0x1000),
+            ..Config::new(32, 6).unwrap()
+        };
+        let instruction_configuration = instruction_configuration(2, AddressingMode::Absolute);
+        let binary_slice = instruction_slice(&binary, &config, 2);
+        let address_space = AddressSpace::new(binary_slice, &config, &instruction_configuration);
+
+        assert_eq!(address_space.start(), 0x1002);
+        assert_eq!(address_space.file_offset(0), Some(0x12));
+        for slice_offset in 0..binary_slice.len() {
+            let address = address_space.address(slice_offset).unwrap();
+            assert_eq!(address, 0x1002 + slice_offset as u64);
+            assert_eq!(address_space.slice_offset(address), Some(slice_offset));
+        }
+        assert_eq!(address_space.address(binary_slice.len()), None);
+        assert_eq!(address_space.slice_offset(0x1001), None);
+        assert_eq!(address_space.slice_offset(0x1040), None);
+    }
+
+    #[test]
+    fn word_addressed_pc_offset_is_in_words() {
+        let binary = [0; 0x20];
+        let config = Config {
+            pc_offset: Some(0x800),
+            ..Config::new(16, 4).unwrap()
+        };
+        let instruction_configuration = instruction_configuration(0, AddressingMode::AbsoluteWord);
+        let address_space = AddressSpace::new(&binary, &config, &instruction_configuration);
+        assert_eq!(address_space.address(0), Some(0x1000));
+        assert_eq!(address_space.slice_offset(0x101e), Some(0x1e));
+    }
+
+    #[test]
+    fn padding_of_a_memory_map_has_no_address() {
+        // Code at 0x1000 and at 0x2004, which starts 4 bytes of padding into its 16 byte line of the image
+        let memory_map: MemoryMap = "0x0 0x10 0x1000 r-x\n0x20 0x2a 0x2004 r-x".parse().unwrap();
+        let binary: Vec<u8> = (0..0x2a).collect();
+        let image = memory_map.image(&binary).unwrap();
+        let config = Config {
+            memory_map: Some(memory_map),
+            ..Config::new(16, 4).unwrap()
+        };
+        let instruction_configuration = instruction_configuration(0, AddressingMode::Absolute);
+        let address_space = AddressSpace::new(&image, &config, &instruction_configuration);
+
+        assert_eq!(address_space.start(), 0x1000);
+        assert_eq!(address_space.code_ranges(), vec![[0, 0x10], [0x14, 0x1e]]);
+        for slice_offset in 0x10..0x14 {
+            assert_eq!(address_space.address(slice_offset), None);
+            assert_eq!(address_space.file_offset(slice_offset), None);
+        }
+        for slice_offset in (0..0x10).chain(0x14..0x1e) {
+            let address = address_space.address(slice_offset).unwrap();
+            assert_eq!(address_space.slice_offset(address), Some(slice_offset));
+        }
+        assert_eq!(address_space.address(0x14), Some(0x2004));
+        assert_eq!(address_space.file_offset(0x14), Some(0x20));
+        assert_eq!(address_space.slice_offset(0x2000), None);
+
+        // A slice further into the image starts at the address of its first byte
+        let binary_slice = instruction_slice(&image, &config, 2);
+        let address_space = AddressSpace::new(
+            binary_slice,
+            &config,
+            &InstructionConfiguration {
+                byte_offset: 2,
+                ..instruction_configuration
+            },
+        );
+        assert_eq!(address_space.address(0), Some(0x1002));
+        assert_eq!(address_space.slice_offset(0x1000), None);
+        assert_eq!(address_space.slice_offset(0x2004), Some(0x12));
+    }
+
+    #[test]
+    fn packed_instructions_are_addressed_in_instructions() {
+        let binary = [0; 0x30];
+        let config = Config {
+            bit_packed: true,
+            pc_offset: Some(0x100),
+            ..Config::new(12, 4).unwrap()
+        };
+        let instruction_configuration = instruction_configuration(0, AddressingMode::Absolute);
+        let address_space = AddressSpace::new(&binary, &config, &instruction_configuration);
+        assert_eq!(address_space.address(3), Some(0x103));
+        assert_eq!(address_space.file_offset(3), Some(4));
+        assert_eq!(address_space.slice_offset(0x11f), Some(0x1f));
+        assert_eq!(address_space.slice_offset(0x120), None);
+    }
+}
@@ -0,0 +1,782 @@
+use std::sync::Arc;
+
+use itertools::{iproduct, Itertools};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::address_space::AddressSpace;
+use crate::base_address::{group_by_remainder, vote_bases};
+use crate::call_graph::CallGraph;
+use crate::candidates_opcodes::{call_opcode_histogram, ret_candidates, top_call_candidates};
+use crate::edges::{
+    absolute_call_targets, filter_valid_edges, filter_valid_edges_in_stream, find_potential_edges,
+    function_starts,
+};
+use crate::functions::{recover_functions, FunctionTable};
+use crate::instr_len::instr_len_hypotheses;
+use crate::iter_instructions::{
+    instruction_slice, iter_instruction_stream, iter_potential_instruction_configuration,
+    InstructionConfiguration,
+};
+use crate::min_heap::{Candidate, MinHeap};
+use crate::prelude::*;
+
+/// Entry point of the library. Wraps a `Config` and lets callers adjust it builder-style before running
+/// the analysis on a binary, i.e.
+///
+/// `Analysis::new(32, 6)?.endiannes(Endiannes::Big).left_shift_call_operand(2).run(&binary)`
+pub struct Analysis {
+    config: Config,
+}
+
+impl Analysis {
+    /// Instruction length and call opcode length are the only parameters without a sensible default. Fails if the
+    /// call opcode does not fit the instruction.
+    pub fn new(instr_len: u64, call_opcode_len: u64) -> Result<Analysis, String> {
+        Ok(Analysis {
+            config: Config::new(instr_len, call_opcode_len)?,
+        })
+    }
+
+    pub fn from_config(config: Config) -> Analysis {
+        Analysis { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn length_rule(mut self, length_rule: LengthRule) -> Analysis {
+        self.config.length_rule = Some(length_rule);
+        self
+    }
+
+    pub fn endiannes(mut self, endiannes: Endiannes) -> Analysis {
+        self.config.endiannes = endiannes;
+        self
+    }
+
+    pub fn addressing_mode(mut self, addressing_mode: AddressingMode) -> Analysis {
+        self.config.addressing_mode = addressing_mode;
+        self
+    }
+
+    pub fn file_offset(mut self, start: usize, end: usize) -> Analysis {
+        self.config.file_offset = Some([start, end]);
+        self
+    }
+
+    /// Analyses the code regions of the map together, `run` then takes the image from `MemoryMap::image`
+    pub fn memory_map(mut self, memory_map: MemoryMap) -> Analysis {
+        self.config.memory_map = Some(memory_map);
+        self
+    }
+
+    pub fn pc_offset(mut self, pc_offset: u64) -> Analysis {
+        self.config.pc_offset = Some(pc_offset);
+        self
+    }
+
+    /// Added to the address of the call for pc relative targets, None tries no bias, one and two instructions
+    pub fn pc_bias(mut self, pc_bias: Option<i64>) -> Analysis {
+        self.config.pc_bias = pc_bias;
+        self
+    }
+
+    pub fn region_size(mut self, region_size: u64) -> Analysis {
+        self.config.region_size = region_size;
+        self
+    }
+
+    pub fn left_shift_call_operand(mut self, left_shift_call_operand: u64) -> Analysis {
+        self.config.left_shift_call_operand = Some(left_shift_call_operand);
+        self
+    }
+
+    /// Tries shifts 0 to 3 and the byte length of the instruction instead of a given shift
+    pub fn detect_left_shift_call_operand(mut self) -> Analysis {
+        self.config.left_shift_call_operand = None;
+        self
+    }
+
+    pub fn nr_cand(mut self, nr_cand: usize) -> Analysis {
+        self.config.nr_cand = nr_cand;
+        self
+    }
+
+    pub fn call_search_range(mut self, start: usize, end: usize) -> Analysis {
+        self.config.call_search_range = [start, end];
+        self
+    }
+
+    pub fn ret_search_range(mut self, start: usize, end: usize) -> Analysis {
+        self.config.ret_search_range = [start, end];
+        self
+    }
+
+    pub fn ret_func_dist(mut self, ret_func_dist: usize) -> Analysis {
+        self.config.ret_func_dist = ret_func_dist;
+        self
+    }
+
+    pub fn call_operand_layout(mut self, call_operand_layout: OperandLayout) -> Analysis {
+        self.config.call_operand_layout = call_operand_layout;
+        self
+    }
+
+    /// Bases tried by `infer_pc_offset` are multiples of `pc_offset_align`
+    pub fn pc_offset_align(mut self, pc_offset_align: u64) -> Analysis {
+        self.config.pc_offset_align = pc_offset_align;
+        self
+    }
+
+    /// Also try layouts where the call operand is split into at most `max_segments` segments and reordered
+    pub fn search_call_operand_layout(mut self, max_segments: usize) -> Analysis {
+        self.config.call_operand_layout_search = Some(max_segments);
+        self
+    }
+
+    /// Scores the call and ret opcode pairs instead of `EdgeScorer`, see `Scorer`
+    pub fn scorer(mut self, scorer: impl Scorer + 'static) -> Analysis {
+        self.config.scorer = Arc::new(scorer);
+        self
+    }
+
+    pub fn parallell(mut self, parallell: bool) -> Analysis {
+        self.config.parallell = parallell;
+        self
+    }
+
+    /// Runs the analysis, returning the top `nr_cand` candidates sorted by descending probability
+    pub fn run(&self, binary: &[u8]) -> Vec<Candidate> {
+        let config = &self.config;
+        let mut top_candidates: MinHeap = Default::default();
+
+        // Synchronous
+        if !config.parallell {
+            for (binary_slice, instruction_configuration) in
+                iter_potential_instruction_configuration(binary, config)
+            {
+                analyse_instructions(
+                    binary_slice,
+                    config,
+                    &instruction_configuration,
+                    &top_candidates,
+                )
+            }
+        }
+        // Parralell, speedup ~ min(num_cores, instr_byte_len), i.e given 32 bit instr and modern pc => 4x speedup
+        else {
+            iter_potential_instruction_configuration(binary, config)
+                .collect::<Vec<_>>()
+                .par_iter()
+                .for_each(|(binary_slice, instruction_configuration)| {
+                    analyse_instructions(
+                        binary_slice,
+                        config,
+                        instruction_configuration,
+                        &top_candidates,
+                    )
+                });
+        }
+
+        top_candidates.get_result()
+    }
+
+    /// Calls of a candidate from `run` that `filter_valid_edges` accepts, as (call site, target) addresses sorted
+    /// by call site, see `AddressSpace`
+    pub fn call_edges(&self, binary: &[u8], candidate: &Candidate) -> Vec<(u64, u64)> {
+        let config = self.candidate_config(candidate);
+        let instruction_configuration = &candidate.configuration;
+        let binary_slice =
+            instruction_slice(binary, &config, instruction_configuration.byte_offset);
+
+        let address_space = AddressSpace::new(binary_slice, &config, instruction_configuration);
+        valid_edges(binary_slice, &config, candidate)
+            .into_iter()
+            .filter_map(|(from, to)| {
+                Some((address_space.address(from)?, address_space.address(to)?))
+            })
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Functions of a candidate from `run`, see `recover_functions`
+    pub fn functions(&self, binary: &[u8], candidate: &Candidate) -> FunctionTable {
+        let config = self.candidate_config(candidate);
+        let instruction_configuration = &candidate.configuration;
+        let binary_slice =
+            instruction_slice(binary, &config, instruction_configuration.byte_offset);
+
+        let address_space = AddressSpace::new(binary_slice, &config, instruction_configuration);
+        recover_functions(
+            binary_slice,
+            candidate.ret_opcode,
+            &config,
+            &instruction_configuration.endiannes,
+            &address_space,
+            &valid_edges(binary_slice, &config, candidate),
+        )
+    }
+
+    // The config a candidate from `run` was found with, for finding its edges again
+    fn candidate_config(&self, candidate: &Candidate) -> Config {
+        Config {
+            call_opcode_mask: candidate.call_opcode_mask,
+            call_opcode_len_search: None,
+            call_operand_layout: candidate.call_operand_layout.clone(),
+            ..self.config.clone()
+        }
+    }
+
+    /// Call graph of a candidate from `run`, from its `call_edges`
+    pub fn call_graph(&self, binary: &[u8], candidate: &Candidate) -> CallGraph {
+        let binary_slice =
+            instruction_slice(binary, &self.config, candidate.configuration.byte_offset);
+        let address_space = AddressSpace::new(binary_slice, &self.config, &candidate.configuration);
+        CallGraph::new(&self.call_edges(binary, candidate), address_space.start())
+    }
+}
+
+impl Analysis {
+    /// Tries the rules from `LengthRule::hypotheses` and scores each by the best candidate its instruction stream
+    /// produces. Returns the `nr_cand` best rules with their best candidate, sorted by descending probability
+    /// weighted by valid edges. Rules with a ret opcode of 0, or less than a tenth of the most valid edges, are
+    /// left out.
+    pub fn infer_length_rule(&self, binary: &[u8]) -> Vec<(LengthRule, Candidate)> {
+        let config = &self.config;
+        let (parcel_len, swap_parcels) = match &config.length_rule {
+            Some(length_rule) => (length_rule.parcel_len, length_rule.swap_parcels),
+            None => (16, false),
+        };
+
+        let score = |length_rule: LengthRule| {
+            let analysis = Analysis::from_config(Config {
+                length_rule: Some(length_rule.clone()),
+                nr_cand: 1,
+                ..config.clone()
+            });
+            let best = analysis.run(binary).into_iter().next();
+            best.map(|candidate| (length_rule, candidate))
+        };
+
+        let hypotheses = LengthRule::hypotheses(parcel_len, config.instr_len, swap_parcels);
+        let mut rules: Vec<(LengthRule, Candidate)> = if config.parallell {
+            hypotheses.into_par_iter().filter_map(score).collect()
+        } else {
+            hypotheses.into_iter().filter_map(score).collect()
+        };
+
+        // Rules that split the stream badly can reach a high probability from a handful of calls, thus rules are
+        // ranked by how many valid edges back their probability. Returns of 0 are padding rather than code.
+        let most_valid_edges = rules
+            .iter()
+            .map(|(_, candidate)| candidate.valid_edges)
+            .max()
+            .unwrap_or(0);
+        rules.retain(|(_, candidate)| {
+            candidate.ret_opcode != 0 && candidate.valid_edges * 10 >= most_valid_edges.max(1)
+        });
+        let evidence = |candidate: &Candidate| candidate.probability * candidate.valid_edges as f64;
+        rules.sort_by(|(_, a), (_, b)| evidence(b).total_cmp(&evidence(a)).then_with(|| a.cmp(b)));
+        rules.truncate(config.nr_cand);
+        rules
+    }
+}
+
+impl Analysis {
+    /// Analyses the binary with the `nr_instr_len` most likely instruction lengths from `instr_len_hypotheses`,
+    /// see `Config::with_instr_len`. Returns the `nr_cand` best lengths with their best candidate, sorted by
+    /// descending probability.
+    pub fn infer_instr_len(
+        &self,
+        binary: &[u8],
+        nr_instr_len: usize,
+    ) -> Result<Vec<(u64, Candidate)>, String> {
+        let config = &self.config;
+        let [start, end] = config.file_offset.unwrap_or([0, binary.len()]);
+
+        let mut lengths = Vec::new();
+        for (instr_len, _score) in instr_len_hypotheses(&binary[start..end])
+            .into_iter()
+            .take(nr_instr_len)
+        {
+            let analysis = Analysis::from_config(Config {
+                nr_cand: 1,
+                ..config.with_instr_len(instr_len)?
+            });
+            if let Some(candidate) = analysis.run(binary).into_iter().next() {
+                lengths.push((instr_len, candidate));
+            }
+        }
+
+        lengths.sort_by(|(_, a), (_, b)| a.cmp(b));
+        lengths.truncate(config.nr_cand);
+        Ok(lengths)
+    }
+}
+
+impl Analysis {
+    /// Finds the `pc_offset` of a binary with absolute calls by voting. For every hypothesis and pair of call and ret
+    /// candidates, each call target votes for the bases that put it on one of the `function_starts`, thus the votes
+    /// of a base are the number of edges `filter_valid_edges` accepts with it. Only multiples of `pc_offset_align`
+    /// are considered. The bases with the most votes are analysed, returns the `nr_cand` best
+    /// (pc_offset, votes, candidate) sorted by descending probability.
+    pub fn infer_pc_offset(&self, binary: &[u8]) -> Vec<(u64, usize, Candidate)> {
+        let config = &self.config;
+
+        let hypotheses: Vec<_> = iter_potential_instruction_configuration(binary, config)
+            .filter(|(_, instruction_configuration)| {
+                matches!(
+                    instruction_configuration.addressing_mode,
+                    AddressingMode::Absolute | AddressingMode::AbsoluteWord
+                )
+            })
+            .collect();
+
+        let vote = |index: usize| {
+            let (binary_slice, instruction_configuration) = &hypotheses[index];
+            vote_pc_offset(binary_slice, config, instruction_configuration)
+                .into_iter()
+                .map(move |(votes, pc_offset)| (votes, pc_offset, index))
+                .collect::<Vec<_>>()
+        };
+        let mut votes: Vec<_> = if config.parallell {
+            (0..hypotheses.len())
+                .into_par_iter()
+                .flat_map(vote)
+                .collect()
+        } else {
+            (0..hypotheses.len()).flat_map(vote).collect()
+        };
+
+        // Best votes first, each base is only analysed with the hypothesis that gave it the most votes
+        votes.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+        let mut seen: Vec<u64> = Vec::new();
+        votes.retain(|&(_, pc_offset, _)| {
+            let new = !seen.contains(&pc_offset);
+            seen.push(pc_offset);
+            new
+        });
+
+        let mut pc_offsets: Vec<(u64, usize, Candidate)> = votes
+            .into_iter()
+            .take(config.nr_cand)
+            .filter_map(|(votes, pc_offset, index)| {
+                let (binary_slice, instruction_configuration) = &hypotheses[index];
+                let config = Config {
+                    pc_offset: Some(pc_offset),
+                    nr_cand: 1,
+                    ..config.clone()
+                };
+                let mut top_candidates: MinHeap = Default::default();
+                analyse_instructions(
+                    binary_slice,
+                    &config,
+                    instruction_configuration,
+                    &top_candidates,
+                );
+                let best = top_candidates.get_result().into_iter().next();
+                best.map(|candidate| (pc_offset, votes, candidate))
+            })
+            .collect();
+
+        pc_offsets.sort_by(|(_, _, a), (_, _, b)| a.cmp(b));
+        pc_offsets
+    }
+}
+
+// Returns the `nr_cand` best (votes, pc_offset) over all call and ret candidate pairs, see `Analysis::infer_pc_offset`
+fn vote_pc_offset(
+    binary_slice: &[u8],
+    config: &Config,
+    instruction_configuration: &InstructionConfiguration,
+) -> Vec<(usize, u64)> {
+    let endiannes = &instruction_configuration.endiannes;
+    let pc_offset_align = config.pc_offset_align;
+
+    // Bases of word addressed targets are voted on in words, see `AddressSpace`
+    let address_scale = match instruction_configuration.addressing_mode {
+        AddressingMode::AbsoluteWord => config.word_addr_len() as u64,
+        _ => 1,
+    };
+
+    // Addresses with a pc_offset of 0 are the offsets from the start of the analysed range
+    let zero_based_config = Config {
+        pc_offset: Some(0),
+        ..config.clone()
+    };
+    let address_space =
+        AddressSpace::new(binary_slice, &zero_based_config, instruction_configuration);
+
+    // Function starts of each ret candidate in operand units, see `vote_bases`
+    let function_starts: Vec<FxHashMap<u64, Vec<u64>>> =
+        ret_candidates(binary_slice, config, endiannes)
+            .into_iter()
+            .map(|(ret_candidate, _ret_count)| {
+                let starts = function_starts(binary_slice, ret_candidate, config, endiannes);
+                group_by_remainder(
+                    starts
+                        .into_iter()
+                        .filter_map(|start| address_space.address(start))
+                        .filter(|address| address % address_scale == 0)
+                        .map(|address| address / address_scale),
+                    pc_offset_align,
+                )
+            })
+            .collect();
+
+    let call_opcode_histogram = match call_opcode_configs(config).last() {
+        Some(widest_config) => call_opcode_histogram(binary_slice, widest_config, endiannes),
+        None => return Vec::new(),
+    };
+
+    let mut best: FxHashMap<u64, usize> = Default::default();
+    for config in call_opcode_configs(config).iter() {
+        let call_operand_layouts = match config.call_operand_layout_search {
+            Some(max_segments) => config.call_operand_layout.search_space(max_segments),
+            None => vec![config.call_operand_layout.clone()],
+        };
+
+        for (&(call_candidate, _call_count), call_operand_layout) in iproduct!(
+            top_call_candidates(&call_opcode_histogram, config).iter(),
+            call_operand_layouts.iter()
+        ) {
+            let targets = absolute_call_targets(
+                binary_slice,
+                call_candidate,
+                config,
+                instruction_configuration,
+                call_operand_layout,
+            );
+
+            for function_starts_by_remainder in function_starts.iter() {
+                let votes = vote_bases(&targets, function_starts_by_remainder, pc_offset_align);
+                // Keeps the most votes of each base over all pairs
+                for (votes, pc_offset) in votes
+                    .into_iter()
+                    .map(|(pc_offset, votes)| (votes, pc_offset))
+                    .sorted_unstable_by(|a, b| b.cmp(a))
+                    .take(config.nr_cand)
+                {
+                    let best_votes = best.entry(pc_offset).or_insert(0);
+                    *best_votes = votes.max(*best_votes);
+                }
+            }
+        }
+    }
+
+    best.into_iter()
+        .map(|(pc_offset, votes)| (votes, pc_offset))
+        .sorted_unstable_by(|a, b| b.cmp(a))
+        .take(config.nr_cand)
+        .collect()
+}
+
+// One config per call opcode length when searching for it, lengths that do not fit the instruction are skipped
+fn call_opcode_configs(config: &Config) -> Vec<Config> {
+    match config.call_opcode_len_search {
+        Some([min, max]) => (min..=max)
+            .filter_map(|call_opcode_len| config.with_call_opcode_len(call_opcode_len).ok())
+            .collect(),
+        None => vec![config.clone()],
+    }
+}
+
+pub fn analyse_instructions(
+    binary_slice: &[u8],
+    config: &Config,
+    instruction_configuration: &InstructionConfiguration,
+    top_candidates: &MinHeap,
+) {
+    let endiannes = &instruction_configuration.endiannes;
+
+    // The ret mask does not depend on the call opcode length, thus the ret candidates are shared by all lengths
+    let ret_cand = ret_candidates(binary_slice, config, endiannes);
+
+    // Variable length instructions are decoded once, rather than for every call and ret candidate pair
+    let instruction_stream: Option<Vec<_>> = config
+        .length_rule
+        .as_ref()
+        .map(|length_rule| iter_instruction_stream(binary_slice, endiannes, length_rule).collect());
+
+    let call_opcode_configs = call_opcode_configs(config);
+    // Shorter opcodes are a subset of the longest, thus its histogram is counted once and reused
+    let call_opcode_histogram = match call_opcode_configs.last() {
+        Some(widest_config) => call_opcode_histogram(binary_slice, widest_config, endiannes),
+        None => return,
+    };
+
+    // A longer opcode whose extra bits are zero is the same opcode as a shorter one, i.e 0x0c000000 at 6, 7 and 8
+    // bits, it is only analysed at the shortest length. The lengths are tried shortest first.
+    let mut analysed_call_candidates: FxHashSet<u64> = Default::default();
+
+    // The config of each call opcode length shadows the outer one
+    for config in call_opcode_configs.iter() {
+        // We assume call instruction is among call candidates, and ret instruction for ret_candidates
+        let call_cand: Vec<(u64, usize)> = top_call_candidates(&call_opcode_histogram, config)
+            .into_iter()
+            .filter(|&(call_candidate, _)| analysed_call_candidates.insert(call_candidate))
+            .collect();
+
+        // The layout only affects edge finding, thus the histograms above are shared by all layouts
+        let call_operand_layouts = match config.call_operand_layout_search {
+            Some(max_segments) => config.call_operand_layout.search_space(max_segments),
+            None => vec![config.call_operand_layout.clone()],
+        };
+
+        for (&(call_candidate, call_count), call_operand_layout) in
+            iproduct!(call_cand.iter(), call_operand_layouts.iter())
+        {
+            // Valid addresses for instructions of the given call candidates
+            let potential_edges = find_potential_edges(
+                binary_slice,
+                call_candidate,
+                config,
+                instruction_configuration,
+                call_operand_layout,
+            );
+
+            for &(ret_candidate, ret_count) in ret_cand.iter() {
+                // valid addresses where there is a return preceding it
+                let valid_edges = match &instruction_stream {
+                    Some(instructions) => filter_valid_edges_in_stream(
+                        ret_candidate,
+                        config,
+                        &potential_edges,
+                        instructions,
+                    ),
+                    None => filter_valid_edges(
+                        binary_slice,
+                        ret_candidate,
+                        config,
+                        &potential_edges,
+                        endiannes,
+                    ),
+                };
+
+                let probability = config.scorer.score(&Evidence {
+                    call_count,
+                    ret_count,
+                    potential_edges: &potential_edges,
+                    valid_edges: &valid_edges,
+                });
+                // I.e a scorer dividing by a count of zero
+                let probability = if probability.is_nan() {
+                    0.0
+                } else {
+                    probability
+                };
+
+                // Add to heap if high probability
+                top_candidates.add_maybe(
+                    config.nr_cand,
+                    Candidate {
+                        probability,
+                        call_opcode: call_candidate,
+                        ret_opcode: ret_candidate,
+                        call_operand_layout: call_operand_layout.clone(),
+                        call_opcode_mask: config.call_opcode_mask,
+                        configuration: *instruction_configuration,
+                        call_count,
+                        ret_count,
+                        potential_edges: potential_edges.len(),
+                        valid_edges: valid_edges.len(),
+                    },
+                )
+            }
+        }
+    }
+}
+
+// Valid edges of a candidate in slice offsets, `config` is its `candidate_config`
+fn valid_edges(binary_slice: &[u8], config: &Config, candidate: &Candidate) -> Vec<(usize, usize)> {
+    let instruction_configuration = &candidate.configuration;
+    let potential_edges = find_potential_edges(
+        binary_slice,
+        candidate.call_opcode,
+        config,
+        instruction_configuration,
+        &candidate.call_operand_layout,
+    );
+    filter_valid_edges(
+        binary_slice,
+        candidate.ret_opcode,
+        config,
+        &potential_edges,
+        &instruction_configuration.endiannes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_opcode_must_fit_the_instruction() {
+        assert!(Analysis::new(32, 6).is_ok());
+        assert!(Analysis::new(32, 0).is_err());
+        assert!(Analysis::new(32, 40).is_err());
+    }
+
+    #[test]
+    fn call_opcodes_are_found_at_their_shortest_length() {
+        // Calls to the instruction after each return, the 6 bit jal opcode 0b000011 is followed by zero bits
+        let binary: Vec<u8> = (0..64u32)
+            .flat_map(|index| {
+                match index % 4 {
+                    0 => 0x0c000000 | (index + 4),
+                    3 => 0x03e00008,
+                    _ => 0x24000000 | index,
+                }
+                .to_be_bytes()
+            })
+            .collect();
+        let candidates = Analysis::from_config(Config {
+            call_opcode_len_search: Some([4, 8]),
+            ..Config::new(32, 4).unwrap()
+        })
+        .endiannes(Endiannes::Big)
+        .addressing_mode(AddressingMode::Absolute)
+        .left_shift_call_operand(2)
+        .pc_offset(0)
+        .nr_cand(10)
+        .run(&binary);
+        let jal: Vec<u32> = candidates
+            .iter()
+            .filter(|candidate| candidate.call_opcode == 0x0c000000)
+            .map(|candidate| candidate.call_opcode_len())
+            .collect();
+        assert_eq!(jal, vec![6]);
+    }
+
+    #[test]
+    fn absolute_targets_vote_for_their_base() {
+        // 51 functions of five instructions loaded at 0x400000, each calling the function three after it
+        let base = 0x400000;
+        let binary: Vec<u8> = (0..255u32)
+            .flat_map(|index| {
+                match index % 5 {
+                    0 => 0x0c000000 | ((base + ((index + 15) % 255) * 4) >> 2),
+                    4 => 0x03e00008,
+                    _ => 0x24000000 | index,
+                }
+                .to_be_bytes()
+            })
+            .collect();
+        // Functions start right after a return, otherwise the other instructions are taken as starts too
+        let config = Config {
+            endiannes: Endiannes::Big,
+            ret_func_dist: 1,
+            ..Config::new(32, 6).unwrap()
+        };
+        let instruction_configuration = InstructionConfiguration {
+            byte_offset: 0,
+            endiannes: Endiannes::Big,
+            addressing_mode: AddressingMode::Absolute,
+            pc_bias: 0,
+            left_shift_call_operand: 2,
+        };
+        let votes = vote_pc_offset(&binary, &config, &instruction_configuration);
+        // Every call but the ones to the first function, which no return precedes
+        assert_eq!(votes[0], (50, base as u64));
+    }
+
+    struct NanScorer;
+
+    impl Scorer for NanScorer {
+        fn score(&self, _: &Evidence) -> f64 {
+            f64::NAN
+        }
+    }
+
+    #[test]
+    fn nan_scores_are_zero() {
+        // Calls to the instruction after each return
+        let binary: Vec<u8> = (0..64u32)
+            .flat_map(|index| {
+                match index % 4 {
+                    0 => 0x0c100000 | (index + 4),
+                    3 => 0x03e00008,
+                    _ => 0x24000000 | index,
+                }
+                .to_be_bytes()
+            })
+            .collect();
+        let candidates = Analysis::new(32, 6)
+            .unwrap()
+            .endiannes(Endiannes::Big)
+            .addressing_mode(AddressingMode::Absolute)
+            .left_shift_call_operand(2)
+            .scorer(NanScorer)
+            .run(&binary);
+        assert!(!candidates.is_empty());
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.probability == 0.0));
+    }
+
+    // RISC-V JAL with ra as the link register
+    fn jal(caller: u32, target: u32) -> u32 {
+        let imm = target.wrapping_sub(caller);
+        ((imm >> 20 & 1) << 31)
+            | ((imm >> 1 & 0x3ff) << 21)
+            | ((imm >> 11 & 1) << 20)
+            | ((imm >> 12 & 0xff) << 12)
+            | 0xef
+    }
+
+    #[test]
+    fn length_rules_are_ranked_by_their_evidence() {
+        // Functions of compressed and full instructions, each calling another and ending in c.ret
+        let mut seed = 0x2545f491u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let functions: Vec<Vec<u32>> = (0..24)
+            .map(|_| (0..8).map(|_| random()).collect())
+            .collect();
+        let starts: Vec<u32> = functions
+            .iter()
+            .scan(0, |start, words| {
+                let function_start = *start;
+                *start += words
+                    .iter()
+                    .map(|word| if word % 2 == 0 { 2 } else { 4 })
+                    .sum::<u32>()
+                    + 6;
+                Some(function_start)
+            })
+            .collect();
+        let mut binary = Vec::new();
+        for (index, words) in functions.iter().enumerate() {
+            for word in words {
+                if word % 2 == 0 {
+                    binary.extend_from_slice(&((word >> 16 & !0x3) as u16 | 0x1).to_le_bytes());
+                } else {
+                    binary.extend_from_slice(&((word & !0x7f) | 0x13).to_le_bytes());
+                }
+            }
+            let target = starts[(index + 5) % starts.len()];
+            binary.extend_from_slice(&jal(binary.len() as u32, target).to_le_bytes());
+            binary.extend_from_slice(&0x8082u16.to_le_bytes());
+        }
+
+        let rules =
+            Analysis::from_config(Config::from_bit_ranges(32, [0, 11], [12, 31], None).unwrap())
+                .call_operand_layout(
+                    OperandLayout::new(32, parse_bit_ranges("31,19:12,20,30:21").unwrap()).unwrap(),
+                )
+                .left_shift_call_operand(1)
+                .endiannes(Endiannes::Little)
+                .addressing_mode(AddressingMode::Relative)
+                .infer_length_rule(&binary);
+        // Rules with more long instructions also reach probability 1, from fewer of the calls
+        assert_eq!(rules[0].0.to_string(), "0x3=0x3:32");
+        assert_eq!(rules[0].1.valid_edges, 24);
+        assert!(rules.iter().all(|(_, candidate)| candidate.ret_opcode != 0));
+    }
+}
@@ -0,0 +1,157 @@
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::iter_instructions::iter_instructions;
+use crate::prelude::*;
+
+/// Finds the address the binary is loaded at from tables of absolute pointers, like rbasefind. Every aligned
+/// `pointer_len` bit word votes for the bases that put it on the start of a string, the base with the most votes is
+/// most likely right. Only multiples of `align` are considered, unknown endiannes tries both.
+/// Returns the `nr_cand` best (base, votes, endiannes) sorted by descending votes.
+pub fn find_base_address(
+    binary: &[u8],
+    endiannes: &Endiannes,
+    pointer_len: u64,
+    min_string_len: usize,
+    align: u64,
+    nr_cand: usize,
+) -> Vec<(u64, usize, Endiannes)> {
+    let endiannes = if let Endiannes::Unknown = endiannes {
+        vec![Endiannes::Big, Endiannes::Little]
+    } else {
+        vec![*endiannes]
+    };
+
+    let string_starts = group_by_remainder(string_starts(binary, min_string_len), align);
+
+    endiannes
+        .into_iter()
+        .flat_map(|endiannes| {
+            let pointers = pointers(binary, &endiannes, pointer_len);
+            vote_bases(&pointers, &string_starts, align)
+                .into_iter()
+                .map(move |(base, votes)| (base, votes, endiannes))
+        })
+        .sorted_unstable_by(|(_, a, _), (_, b, _)| b.cmp(a))
+        .take(nr_cand)
+        .collect()
+}
+
+/// Offsets of the NUL terminated runs of at least `min_string_len` printable ASCII characters, in increasing order
+pub fn string_starts(binary: &[u8], min_string_len: usize) -> Vec<u64> {
+    let mut string_starts = Vec::new();
+    let mut start = 0;
+    for (offset, &byte) in binary.iter().enumerate() {
+        if byte == 0 && offset - start >= min_string_len {
+            string_starts.push(start as u64);
+        }
+        if !(byte.is_ascii_graphic() || byte.is_ascii_whitespace()) {
+            start = offset + 1;
+        }
+    }
+    string_starts
+}
+
+/// Distinct non zero words aligned to their size, the values that may be pointers
+pub fn pointers(binary: &[u8], endiannes: &Endiannes, pointer_len: u64) -> Vec<u64> {
+    let pointers: FxHashSet<u64> = iter_instructions(binary, endiannes, pointer_len)
+        .filter(|&pointer| pointer != 0)
+        .collect();
+    pointers.into_iter().collect()
+}
+
+/// Groups increasing offsets by their remainder modulo `align`, for `vote_bases`
+pub fn group_by_remainder(
+    offsets: impl IntoIterator<Item = u64>,
+    align: u64,
+) -> FxHashMap<u64, Vec<u64>> {
+    let mut offsets_by_remainder: FxHashMap<u64, Vec<u64>> = Default::default();
+    for offset in offsets {
+        offsets_by_remainder
+            .entry(offset % align)
+            .or_default()
+            .push(offset);
+    }
+    offsets_by_remainder
+}
+
+/// Every address votes for the bases that put it on one of the offsets, returns the votes of each base. A base
+/// `address - offset` is a multiple of `align` only when the address and the offset have the same remainder,
+/// thus only those pairs are visited.
+pub fn vote_bases(
+    addresses: &[u64],
+    offsets_by_remainder: &FxHashMap<u64, Vec<u64>>,
+    align: u64,
+) -> FxHashMap<u64, usize> {
+    let mut votes: FxHashMap<u64, usize> = Default::default();
+    for &address in addresses {
+        if let Some(offsets) = offsets_by_remainder.get(&(address % align)) {
+            for &offset in offsets.iter().take_while(|&&offset| offset <= address) {
+                *votes.entry(address - offset).or_insert(0) += 1;
+            }
+        }
+    }
+    votes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A table of little endian pointers to strings, as loaded at `base`, followed by the strings and other data
+    fn image(base: u64) -> (Vec<u8>, Vec<u64>) {
+        let strings = [
+            "usage: %s [options]",
+            "out of memory",
+            "\tdone\n",
+            "no such file",
+            "version 1.2",
+        ];
+        let mut binary = vec![0u8; 0x40];
+        let mut starts = Vec::new();
+        for (index, string) in strings.iter().enumerate() {
+            let start = binary.len() as u64;
+            starts.push(start);
+            binary[index * 4..index * 4 + 4]
+                .copy_from_slice(&((base + start) as u32).to_le_bytes());
+            binary.extend_from_slice(string.as_bytes());
+            binary.push(0);
+        }
+        // Too short to be a string, and not terminated
+        binary.extend_from_slice(b"ab\0");
+        binary.extend_from_slice(b"unterminated");
+        binary.resize(binary.len().next_multiple_of(4), 0xff);
+        (binary, starts)
+    }
+
+    #[test]
+    fn string_starts_are_terminated_printable_runs() {
+        let (binary, starts) = image(0);
+        assert_eq!(string_starts(&binary, 4), starts);
+    }
+
+    #[test]
+    fn offsets_are_grouped_by_remainder() {
+        let groups = group_by_remainder([0x10, 0x1004, 0x2010, 0x3000], 0x1000);
+        assert_eq!(groups[&0x10], vec![0x10, 0x2010]);
+        assert_eq!(groups[&0x4], vec![0x1004]);
+        assert_eq!(groups[&0x0], vec![0x3000]);
+    }
+
+    #[test]
+    fn pointers_vote_for_the_base_of_their_strings() {
+        let base = 0x8001_0000;
+        let (binary, starts) = image(base);
+        let votes = vote_bases(
+            &pointers(&binary, &Endiannes::Little, 32),
+            &group_by_remainder(string_starts(&binary, 4), 0x1000),
+            0x1000,
+        );
+        assert_eq!(votes[&base], starts.len());
+
+        let found = find_base_address(&binary, &Endiannes::Unknown, 32, 4, 0x1000, 3);
+        assert_eq!(found[0].0, base);
+        assert_eq!(found[0].1, starts.len());
+        assert!(matches!(found[0].2, Endiannes::Little));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt::Write;
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+/// Calls between functions, where the functions are the call targets and each call belongs to the closest function
+/// starting at or before it. Calls before the first function belong to the start of the code.
+pub struct CallGraph {
+    // Function start addresses in increasing order
+    pub functions: Vec<u64>,
+    // (caller, callee, number of calls) in increasing order
+    pub edges: Vec<(u64, u64, usize)>,
+}
+
+impl CallGraph {
+    /// Graph of (call site, target) address pairs, i.e from `Analysis::call_edges`
+    pub fn new(call_edges: &[(u64, u64)], code_start: u64) -> CallGraph {
+        let targets: Vec<u64> = call_edges
+            .iter()
+            .map(|&(_, target)| target)
+            .sorted_unstable()
+            .dedup()
+            .collect();
+        let caller = |call_site: u64| match targets.partition_point(|&target| target <= call_site) {
+            0 => code_start,
+            index => targets[index - 1],
+        };
+
+        let edges: Vec<(u64, u64, usize)> = call_edges
+            .iter()
+            .map(|&(call_site, target)| (caller(call_site), target))
+            .sorted_unstable()
+            .dedup_with_count()
+            .map(|(calls, (caller, callee))| (caller, callee, calls))
+            .collect();
+        let functions = targets
+            .iter()
+            .copied()
+            .chain(edges.iter().map(|&(caller, _, _)| caller))
+            .sorted_unstable()
+            .dedup()
+            .collect();
+
+        CallGraph { functions, edges }
+    }
+
+    /// Number of distinct callers and callees of each function
+    pub fn degrees(&self) -> FxHashMap<u64, [usize; 2]> {
+        let mut degrees: FxHashMap<u64, [usize; 2]> = Default::default();
+        for &(caller, callee, _) in self.edges.iter() {
+            degrees.entry(callee).or_default()[0] += 1;
+            degrees.entry(caller).or_default()[1] += 1;
+        }
+        degrees
+    }
+
+    /// Graphviz graph, nodes are labelled by address and degrees and edges by the number of calls
+    pub fn to_dot(&self) -> String {
+        let degrees = self.degrees();
+        let mut dot = String::from("digraph call_graph {\n    node [shape=box];\n");
+        for &function in self.functions.iter() {
+            let [in_degree, out_degree] = degrees[&function];
+            writeln!(
+                dot,
+                "    \"{:#x}\" [label=\"{:#x}\\nin: {} out: {}\"];",
+                function, function, in_degree, out_degree
+            )
+            .unwrap();
+        }
+        for &(caller, callee, calls) in self.edges.iter() {
+            writeln!(
+                dot,
+                "    \"{:#x}\" -> \"{:#x}\" [label=\"{}\"];",
+                caller, callee, calls
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// GraphML graph, i.e for Gephi, nodes carry their address as label and their degrees, edges their number of
+    /// calls
+    pub fn to_graphml(&self) -> String {
+        let mut graphml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>\n",
+            "  <key id=\"in_degree\" for=\"node\" attr.name=\"in_degree\" attr.type=\"int\"/>\n",
+            "  <key id=\"out_degree\" for=\"node\" attr.name=\"out_degree\" attr.type=\"int\"/>\n",
+            "  <key id=\"calls\" for=\"edge\" attr.name=\"calls\" attr.type=\"int\"/>\n",
+            "  <graph id=\"call_graph\" edgedefault=\"directed\">\n",
+        ));
+        let degrees = self.degrees();
+        let id = |address: u64| xml_escape(&format!("{:#x}", address));
+        for &function in self.functions.iter() {
+            let [in_degree, out_degree] = degrees[&function];
+            writeln!(
+                graphml,
+                "    <node id=\"{}\"><data key=\"label\">{}</data><data key=\"in_degree\">{}</data>\
+                <data key=\"out_degree\">{}</data></node>",
+                id(function),
+                id(function),
+                in_degree,
+                out_degree
+            )
+            .unwrap();
+        }
+        for &(caller, callee, calls) in self.edges.iter() {
+            writeln!(
+                graphml,
+                "    <edge source=\"{}\" target=\"{}\"><data key=\"calls\">{}</data></edge>",
+                id(caller),
+                id(callee),
+                calls
+            )
+            .unwrap();
+        }
+        graphml.push_str("  </graph>\n</graphml>\n");
+        graphml
+    }
+}
+
+// Text as XML character data or attribute value
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two calls from the start of the code to the function at 0x1100, which calls back once
+    fn call_graph() -> CallGraph {
+        CallGraph::new(
+            &[(0x1004, 0x1100), (0x1008, 0x1100), (0x1104, 0x1000)],
+            0x1000,
+        )
+    }
+
+    #[test]
+    fn calls_belong_to_the_function_before_them() {
+        let call_graph = call_graph();
+        assert_eq!(call_graph.functions, vec![0x1000, 0x1100]);
+        assert_eq!(
+            call_graph.edges,
+            vec![(0x1000, 0x1100, 2), (0x1100, 0x1000, 1)]
+        );
+        assert_eq!(call_graph.degrees()[&0x1100], [1, 1]);
+    }
+
+    #[test]
+    fn dot_has_a_line_per_function_and_edge() {
+        let dot = call_graph().to_dot();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(lines.first(), Some(&"digraph call_graph {"));
+        assert!(lines.contains(&"    \"0x1000\" [label=\"0x1000\\nin: 1 out: 1\"];"));
+        assert!(lines.contains(&"    \"0x1000\" -> \"0x1100\" [label=\"2\"];"));
+        assert!(lines.contains(&"    \"0x1100\" -> \"0x1000\" [label=\"1\"];"));
+        assert_eq!(lines.last(), Some(&"}"));
+    }
+
+    #[test]
+    fn graphml_has_a_line_per_function_and_edge() {
+        let graphml = call_graph().to_graphml();
+        let lines: Vec<&str> = graphml.lines().collect();
+        assert!(lines.contains(
+            &"    <node id=\"0x1100\"><data key=\"label\">0x1100</data><data key=\"in_degree\">1</data>\
+            <data key=\"out_degree\">1</data></node>"
+        ));
+        assert!(lines.contains(
+            &"    <edge source=\"0x1000\" target=\"0x1100\"><data key=\"calls\">2</data></edge>"
+        ));
+        assert!(lines.contains(
+            &"    <edge source=\"0x1100\" target=\"0x1000\"><data key=\"calls\">1</data></edge>"
+        ));
+        assert_eq!(lines.last(), Some(&"</graphml>"));
+    }
+
+    #[test]
+    fn xml_special_characters_are_escaped() {
+        assert_eq!(
+            xml_escape("<a & \"b\"> 'c'"),
+            "&lt;a &amp; &quot;b&quot;&gt; &apos;c&apos;"
+        );
+        assert_eq!(xml_escape("0x1000"), "0x1000");
+    }
+}
@@ -1,10 +1,51 @@
 use crate::prelude::*;
-use itertools::Itertools;
+use itertools::{Either, Itertools};
 use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::iter_instructions::iter_instructions;
+use crate::iter_instructions::{
+    iter_instruction_stream, iter_instructions, iter_packed_instructions,
+};
 
 pub fn call_candidates(binary: &[u8], config: &Config, endiannes: &Endiannes) -> Vec<(u64, usize)> {
+    top_call_candidates(&call_opcode_histogram(binary, config, endiannes), config)
+}
+
+/// Counts of the call opcodes given by `config.call_opcode_mask`. A histogram for a mask can be reused for any mask
+/// that is a subset of it, see `top_call_candidates`.
+pub fn call_opcode_histogram(
+    binary: &[u8],
+    config: &Config,
+    endiannes: &Endiannes,
+) -> FxHashMap<u64, usize> {
+    let call_opcode_mask = config.call_opcode_mask;
+
+    let mut counts: FxHashMap<u64, usize> =
+        FxHashMap::with_capacity_and_hasher(1024, Default::default());
+    let mut count = |instr: u64| {
+        counts
+            .entry(instr & call_opcode_mask)
+            .and_modify(|e| *e += 1)
+            .or_insert(1);
+    };
+    if let Some(length_rule) = &config.length_rule {
+        // Only instructions of the configured length can be calls, since the masks are defined for that length
+        iter_instruction_stream(binary, endiannes, length_rule)
+            .filter(|&(_, instr_len, _)| instr_len == config.instr_len)
+            .for_each(|(_, _, instr)| count(instr));
+    } else if config.bit_packed {
+        iter_packed_instructions(binary, endiannes, config.instr_len).for_each(count);
+    } else {
+        iter_instructions(binary, endiannes, config.instr_len).for_each(count);
+    }
+    counts
+}
+
+/// Most common call opcodes for `config.call_opcode_mask`, which must be a subset of the mask the histogram was
+/// counted with
+pub fn top_call_candidates(
+    histogram: &FxHashMap<u64, usize>,
+    config: &Config,
+) -> Vec<(u64, usize)> {
     // Destructure CLI params we need
     let &Config {
         call_opcode_mask,
@@ -13,17 +54,14 @@ pub fn call_candidates(binary: &[u8], config: &Config, endiannes: &Endiannes) ->
     } = config;
 
     let mut counts: FxHashMap<u64, usize> =
-        FxHashMap::with_capacity_and_hasher(1024, Default::default());
-    for instr in iter_instructions(binary, endiannes, config.instr_len) {
-        counts
-            .entry(instr & call_opcode_mask)
-            .and_modify(|e| *e += 1)
-            .or_insert(1);
+        FxHashMap::with_capacity_and_hasher(histogram.len(), Default::default());
+    for (&opcode, &count) in histogram {
+        *counts.entry(opcode & call_opcode_mask).or_insert(0) += count;
     }
 
     counts
         .into_iter()
-        .filter(|(c, _)| c > &10)
+        .filter(|(_, c)| c > &10)
         .sorted_unstable_by_key(|&(_, count)| count)
         .rev()
         .skip(call_search_range[0])
@@ -42,19 +80,41 @@ pub fn ret_candidates(binary: &[u8], config: &Config, endiannes: &Endiannes) ->
     // Super nice optimization, look at a subset if big file, find unique instructions in the subset,
     // Only put the ones found here in the count after, skip rest
     // Assumes that a ret instruction will be found in a subsize of the given size.
+    // Returns may be of any length for variable length instructions
+    let instructions = |binary| match &config.length_rule {
+        Some(length_rule) => Either::Left(
+            iter_instruction_stream(binary, endiannes, length_rule).map(|(_, _, instr)| instr),
+        ),
+        None if config.bit_packed => Either::Right(Either::Left(iter_packed_instructions(
+            binary,
+            endiannes,
+            config.instr_len,
+        ))),
+        None => Either::Right(Either::Right(iter_instructions(
+            binary,
+            endiannes,
+            config.instr_len,
+        ))),
+    };
+
     let counts: FxHashMap<u64, usize> = if binary.len() > 262144
     /* 2 ^ 18 */
     {
         let mut potentials: FxHashSet<u64> =
             FxHashSet::with_capacity_and_hasher(8192, Default::default());
-        let index = (8192 * config.instr_len / BYTE_SIZE) as usize;
-        for instr in iter_instructions(&binary[index..index * 2], endiannes, config.instr_len) {
+        // Bit packed instructions must start on the same bit position as in the whole binary
+        let index = if config.bit_packed {
+            (8192 * config.instr_len / BYTE_SIZE) as usize
+        } else {
+            8192 * config.instr_byte_len()
+        };
+        for instr in instructions(&binary[index..index * 2]) {
             potentials.insert(instr & ret_opcode_mask);
         }
 
         let mut counts: FxHashMap<u64, usize> =
             FxHashMap::with_capacity_and_hasher(1024, Default::default());
-        for instr in iter_instructions(binary, endiannes, config.instr_len) {
+        for instr in instructions(binary) {
             let instr = instr & ret_opcode_mask;
             if potentials.contains(&instr) {
                 counts.entry(instr).and_modify(|e| *e += 1).or_insert(1);
@@ -65,7 +125,7 @@ pub fn ret_candidates(binary: &[u8], config: &Config, endiannes: &Endiannes) ->
         // OPTION 1, simple solution with FxHasher
         let mut counts: FxHashMap<u64, usize> =
             FxHashMap::with_capacity_and_hasher(1024, Default::default());
-        for instr in iter_instructions(binary, endiannes, config.instr_len) {
+        for instr in instructions(binary) {
             counts
                 .entry(instr & ret_opcode_mask)
                 .and_modify(|e| *e += 1)
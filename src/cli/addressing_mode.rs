@@ -5,5 +5,12 @@ pub enum AddressingMode {
     // Register? Not sure if it is feasible to handle that
     Absolute,
     Relative,
+    // Operands count instruction words rather than bytes (Harvard architectures such as AVR, PIC and many DSPs)
+    AbsoluteWord,
+    RelativeWord,
+    // Operand replaces the low bits of the caller's address within a region of --region-size bytes (MIPS J/JAL)
+    Region,
+    // Operand is added to the base of the caller's page of --region-size bytes (ADRP style)
+    Page,
     Unknown,
 }
@@ -1,5 +1,5 @@
 use crate::prelude::*;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use clap_num::maybe_hex;
 
 #[derive(Parser)]
@@ -7,25 +7,66 @@ use clap_num::maybe_hex;
     author,
     version,
     about,
-    override_usage = "binary-analysis-rs <FILE_PATH> -i <INSTR_LEN> -o <OPCODE_LEN>",
-    after_help = "TODO How to use this program"
+    override_usage = "binary-analysis-rs <FILE_PATH> [-i <INSTR_LEN>] [-c <OPCODE_LEN>]",
+    after_help = "TODO How to use this program",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 pub struct Parameters {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub analysis: AnalysisParameters,
+}
+
+// Shared by the default analysis and the subcommands that analyse the binary the same way before doing more
+#[derive(Args)]
+pub struct AnalysisParameters {
     // TODO GIVE all values more explicit names, and just deconstruct them in function prologues instead
-    #[arg()]
-    pub file_path: PathBuf,
+    // Only missing when a subcommand is given
+    #[arg(required = true)]
+    pub file_path: Option<PathBuf>,
+
+    // Detected from the binary if not given, trying the most likely widths and keeping the best
+    #[arg(short = 'i', long, value_name = "int", help = "Instruction Length")]
+    pub instr_len: Option<u64>,
+
+    // Instructions that are not a multiple of 8 bits are packed without padding instead of each being stored in the
+    // lowest bits of the smallest byte container, i.e 16 bits for PIC's 14 bit instructions
+    #[arg(long, default_value = "false", conflicts_with = "length_pattern")]
+    pub bit_packed: bool,
+
+    // Variable length instructions, MASK=VALUE:LEN means a first parcel matching the mask and value starts an
+    // instruction of LEN bits, i.e "0x3=0x3:32" for RISC-V with the C extension. Can be given multiple times.
+    #[arg(long, required = false, value_name = "MASK=VALUE:LEN")]
+    pub length_pattern: Vec<LengthPattern>,
+
+    // Smallest instruction unit for variable length instructions
+    #[arg(long, default_value = "16", value_name = "int")]
+    pub parcel_len: u64,
+
+    // Store the first parcel in the least significant bits for big endian and most significant for little, i.e Thumb-2
+    #[arg(long, default_value = "false")]
+    pub swap_parcels: bool,
+
+    // Find the most likely length rule for a variable length ISA, where instructions are either one parcel or -i bits
+    #[arg(long, default_value = "false")]
+    pub infer_length_rule: bool,
+
+    // Number of most significant bits that make up the call opcode. Without it or bit indexes, the lengths in
+    // --call-opcode-len-range are tried
+    #[arg(short = 'c')]
+    pub call_opcode_len: Option<u64>,
 
+    // Inclusive range of call opcode lengths to try when -c is not given
     #[arg(
-        short = 'i',
         long,
-        required = true,
-        value_name = "int",
-        help = "Instruction Length"
+        number_of_values = 2,
+        default_value = "4 8",
+        value_delimiter = ' '
     )]
-    pub instr_len: u64,
-
-    #[arg(short = 'c', required_unless_present_all=["ret_opcode_index", "call_opcode_index", "call_operand_index"])]
-    pub call_opcode_len: Option<u64>,
+    pub call_opcode_len_range: Vec<u64>,
 
     #[arg(short = 'e', long, default_value = "unknown", value_enum)]
     pub endiannes: Endiannes,
@@ -33,12 +74,14 @@ pub struct Parameters {
     #[arg(short = 'a', long, default_value = "unknown", value_enum)]
     pub addressing_mode: AddressingMode,
 
-    // If one needs to be more explicit about which bits are part of return opcode
+    // If one needs to be more explicit about which bits are part of return opcode, defaults to the whole instruction.
+    // All index pairs are inclusive bit indexes where bit 0 is the least significant bit, i.e "0 6"
     #[arg(
         long,
         number_of_values = 2,
         required = false,
-        conflicts_with = "call_opcode_len"
+        conflicts_with = "call_opcode_len",
+        requires = "call_opcode_index"
     )]
     pub ret_opcode_index: Option<Vec<u64>>,
 
@@ -47,7 +90,8 @@ pub struct Parameters {
         long,
         number_of_values = 2,
         required = false,
-        conflicts_with = "call_opcode_len"
+        conflicts_with = "call_opcode_len",
+        requires = "call_operand_index"
     )]
     pub call_opcode_index: Option<Vec<u64>>,
 
@@ -56,25 +100,85 @@ pub struct Parameters {
         long,
         number_of_values = 2,
         required = false,
-        conflicts_with = "call_opcode_len"
+        conflicts_with = "call_opcode_len",
+        requires = "call_opcode_index"
     )]
     pub call_operand_index: Option<Vec<u64>>,
 
+    // If the call operand is scattered across the instruction, the bit ranges in order from most to least
+    // significant, i.e "31,19:12,20,30:21" for RISC-V JAL. Replaces the operand given by -c or --call-operand-index
+    #[arg(long, required = false, value_name = "ranges")]
+    pub call_operand_bits: Option<String>,
+
+    // Search for the call operand layout by splitting the operand into at most N segments and reordering them,
+    // RISC-V JAL takes 4
+    #[arg(long, required = false, value_name = "N", num_args = 0..=1, default_missing_value = "4")]
+    pub search_call_operand_layout: Option<usize>,
+
     // start, end offset of .text segment of binary file
     #[arg(long, number_of_values=2, required=false, value_parser=maybe_hex::<usize>)]
     pub file_offset: Option<Vec<usize>>,
 
-    // position of first instruction in virtual memory, needed for absolute addressing
-    #[arg(long, default_value="0x400000", value_parser=maybe_hex::<u64>)]
-    pub pc_offset: u64,
+    // File with one region per line as "START END ADDRESS PERMISSIONS", i.e "0x0 0x4000 0x08000000 r-x". All
+    // executable regions are analysed together and calls are resolved across them
+    #[arg(
+        long,
+        required = false,
+        value_name = "path",
+        conflicts_with_all = ["file_offset", "pc_offset", "infer_pc_offset", "bit_packed"]
+    )]
+    pub memory_map: Option<PathBuf>,
+
+    // Analyse the parts of the file whose byte entropy and call opcode histogram look like code instead of the whole
+    // file or --file-offset, joining windows of --code-region-window bytes. The regions are printed for review.
+    // Requires -i unless the file is a container that gives the instruction length
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["memory_map", "infer_pc_offset", "bit_packed"]
+    )]
+    pub detect_code_regions: bool,
 
-    // Relative adress difference between consecutive instructions, defaults to instr_len / 8
+    #[arg(long, default_value="0x1000", value_parser=maybe_hex::<usize>)]
+    pub code_region_window: usize,
+
+    // position of first instruction in virtual memory, needed for absolute addressing. Defaults to the address in the
+    // ELF header, or 0x400000 for raw binaries
+    #[arg(long, required = false, value_parser=maybe_hex::<u64>)]
+    pub pc_offset: Option<u64>,
+
+    // Search for the pc offset of a binary with absolute calls, voting for the bases that put call targets after
+    // returns. Reports the best bases instead of candidates for a given one
+    #[arg(long, default_value = "false", conflicts_with = "infer_length_rule")]
+    pub infer_pc_offset: bool,
+
+    // The inferred pc offset is assumed to be a multiple of this, smaller values are slower
+    #[arg(long, default_value="0x1000", value_parser=maybe_hex::<u64>)]
+    pub pc_offset_align: u64,
+
+    // Size of the region (MIPS J/JAL keeps the upper bits of the caller's address, 256MB) or page (ADRP style)
+    // used by the region and page addressing modes
+    #[arg(long, default_value="0x10000000", value_parser=maybe_hex::<u64>)]
+    pub region_size: u64,
+
+    // Relative adress difference between consecutive instructions, defaults to instr_len / 8 (1 if bit packed)
     #[arg(long, required = false)]
     pub pc_inc: Option<u64>,
 
-    // I.e MIPS and Aarch64 left shifts by 2 since otherwise the last two bits of addressing are unused
-    #[arg(long, default_value = "0")]
-    pub left_shift_call_operand: u64,
+    // Added to the address of the call for pc relative targets, i.e 8 for classic ARM. "unknown" tries no bias, one
+    // and two instructions (pc_inc) and keeps the best
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "int|unknown",
+        allow_hyphen_values = true
+    )]
+    pub pc_bias: String,
+
+    // I.e MIPS and Aarch64 left shifts by 2 since otherwise the last two bits of addressing are unused.
+    // "unknown" tries 0 to 3 and the byte length of the instruction and keeps the best
+    #[arg(long, default_value = "0", value_name = "int|unknown")]
+    pub left_shift_call_operand: String,
 
     // nr of candidate opcodes to return
     #[arg(long, default_value = "3")]
@@ -102,11 +206,61 @@ pub struct Parameters {
     #[arg(long, default_value = "5")]
     pub ret_func_dist: usize,
 
+    // How call and ret opcode pairs are scored, edges weighs calls with a preceding return twice the calls that land
+    // in the binary, targets also rewards rets that are followed by many distinct call targets
+    #[arg(long, default_value = "edges", value_enum)]
+    pub scoring: Scoring,
+
     #[arg(long, default_value = "false")]
     pub parallell: bool,
 
+    // Print the accepted calls of each candidate as call site and target addresses
     #[arg(long, default_value = "false")]
     pub include_instructions: bool,
+
+    // JSON output holds the first calls of each candidate, or all of them with --include-instructions
+    #[arg(long, default_value = "text", value_enum)]
+    pub output_format: OutputFormat,
+
+    // Write the call graph of the best candidate to this file, from caller function to callee
+    #[arg(long, required = false, value_name = "path")]
+    pub call_graph: Option<PathBuf>,
+
+    #[arg(long, default_value = "dot", value_enum)]
+    pub call_graph_format: GraphFormat,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Find the base address from pointers to strings, which can be given as --pc-offset
+    Basefind(BasefindParameters),
+    /// Recover the functions of the best candidate from its calls and returns
+    Functions(Box<AnalysisParameters>),
+}
+
+#[derive(Args)]
+pub struct BasefindParameters {
+    #[arg()]
+    pub file_path: PathBuf,
+
+    #[arg(short = 'e', long, default_value = "unknown", value_enum)]
+    pub endiannes: Endiannes,
+
+    // Pointers are aligned words of this many bits
+    #[arg(long, default_value = "32", value_name = "int")]
+    pub pointer_len: u64,
+
+    // Shorter strings are ignored
+    #[arg(long, default_value = "10", value_name = "int")]
+    pub min_string_len: usize,
+
+    // The base address is assumed to be a multiple of this, smaller values are slower
+    #[arg(long, default_value="0x1000", value_parser=maybe_hex::<u64>)]
+    pub align: u64,
+
+    // nr of base addresses to return
+    #[arg(long, default_value = "3")]
+    pub nr_cand: usize,
 }
 
 pub fn parse_parameters() -> Parameters {
@@ -1,100 +1,533 @@
-use crate::{file::read_file_len, prelude::*};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::scorer::EdgeScorer;
 
 // This struct is added because the cli.rs struct is bloated. I.e the masks in this struct are a combination
 // of fields from the other struct, which we do not need individually.
 // Additionally we want to set some runtime default, such as default pc_inc = instr_len / 8
+#[derive(Clone)]
 pub struct Config {
     // TODO masks etc need to be u64 probably, because for instR_len = 64, mask will be 64 bits
     pub file_path: PathBuf,
     pub instr_len: u64,
+    // Instructions that are not a multiple of 8 bits are packed without padding, rather than each in a container
+    pub bit_packed: bool,
+    // Decides instruction lengths for mixed width ISAs, None means all instructions are instr_len long.
+    // For variable length instructions the masks apply to the instructions that are instr_len long.
+    pub length_rule: Option<LengthRule>,
+    // Search for the length rule instead of analysing with a given one
+    pub infer_length_rule: bool,
+    // Detect the instruction length, instr_len and the masks are then placeholders, see `Config::with_instr_len`
+    pub infer_instr_len: bool,
     pub call_opcode_mask: u64,
+    // Range of call opcode lengths to try, the masks are then placeholders. See `Config::with_call_opcode_len`
+    pub call_opcode_len_search: Option<[u64; 2]>,
     pub ret_opcode_mask: u64,
-    pub call_operand_mask: u64,
-    pub call_operand_signed_mask: u64,
+    pub call_operand_layout: OperandLayout,
+    // Max number of segments to split the call operand into when searching for its layout, None means no search
+    pub call_operand_layout_search: Option<usize>,
     pub endiannes: Endiannes,
     pub addressing_mode: AddressingMode,
-    pub file_offset: [usize; 2],
-    pub pc_offset: u64,
+    // None means the whole binary is analysed
+    pub file_offset: Option<[usize; 2]>,
+    // Code regions analysed together instead of file_offset and pc_offset, the binary is then the image of the map.
+    // See `MemoryMap::image`
+    pub memory_map: Option<MemoryMap>,
+    // Analyse the regions from `detect_code_regions` as a memory map instead of the whole range
+    pub detect_code_regions: bool,
+    pub code_region_window: usize,
+    // None means the default of 0x400000, see `Config::pc_offset`
+    pub pc_offset: Option<u64>,
+    // Search for pc_offset instead of analysing with a given one, see `Analysis::infer_pc_offset`
+    pub infer_pc_offset: bool,
+    // The inferred pc_offset is a multiple of this
+    pub pc_offset_align: u64,
+    // Size of the region or page for the region and page addressing modes, a power of two
+    pub region_size: u64,
     pub pc_inc: u64,
-    pub left_shift_call_operand: u64,
+    // Added to the address of the call for pc relative targets, None means trying 0, pc_inc and 2 * pc_inc
+    pub pc_bias: Option<i64>,
+    // None means trying shifts 0 to 3 and the byte length of the instruction
+    pub left_shift_call_operand: Option<u64>,
     pub nr_cand: usize,
     pub call_search_range: [usize; 2],
     pub ret_search_range: [usize; 2],
     pub ret_func_dist: usize,
+    // Computes the probability of each call and ret opcode pair
+    pub scorer: Arc<dyn Scorer>,
     pub parallell: bool,
     pub include_instructions: bool,
+    pub output_format: OutputFormat,
+    // File to write the call graph of the best candidate to, see `CallGraph`
+    pub call_graph: Option<PathBuf>,
+    pub call_graph_format: GraphFormat,
 }
 
 impl Config {
-    pub fn get() -> Config {
+    /// Config with the same defaults as the CLI, for use as a library. Fails if the call opcode does not fit the
+    /// instruction.
+    pub fn new(instr_len: u64, call_opcode_len: u64) -> Result<Config, String> {
+        let (call_opcode_mask, call_operand_layout, ret_opcode_mask) =
+            masks_from_call_opcode_len(instr_len, call_opcode_len)?;
+
+        Ok(Config {
+            file_path: PathBuf::new(),
+            instr_len,
+            bit_packed: false,
+            length_rule: None,
+            infer_length_rule: false,
+            infer_instr_len: false,
+            call_opcode_mask,
+            call_opcode_len_search: None,
+            ret_opcode_mask,
+            call_operand_layout,
+            call_operand_layout_search: None,
+            endiannes: Endiannes::Unknown,
+            addressing_mode: AddressingMode::Unknown,
+            file_offset: None,
+            memory_map: None,
+            detect_code_regions: false,
+            code_region_window: 0x1000,
+            pc_offset: None,
+            infer_pc_offset: false,
+            pc_offset_align: 0x1000,
+            region_size: 0x10000000,
+            pc_inc: instr_len.div_ceil(BYTE_SIZE),
+            pc_bias: Some(0),
+            left_shift_call_operand: Some(0),
+            nr_cand: 3,
+            call_search_range: [0, 20],
+            ret_search_range: [0, 15],
+            ret_func_dist: 5,
+            scorer: Arc::new(EdgeScorer),
+            parallell: false,
+            include_instructions: false,
+            output_format: OutputFormat::Text,
+            call_graph: None,
+            call_graph_format: GraphFormat::Dot,
+        })
+    }
+
+    /// Config where opcode and operand bits are given as inclusive bit index ranges (bit 0 is the least
+    /// significant), i.e `[0, 6]` for a RISC-V style opcode. A missing ret range means the whole instruction.
+    pub fn from_bit_ranges(
+        instr_len: u64,
+        call_opcode_index: [u64; 2],
+        call_operand_index: [u64; 2],
+        ret_opcode_index: Option<[u64; 2]>,
+    ) -> Result<Config, String> {
+        let (call_opcode_mask, call_operand_layout, ret_opcode_mask) = masks_from_bit_ranges(
+            instr_len,
+            call_opcode_index,
+            call_operand_index,
+            ret_opcode_index,
+        )?;
+
+        Ok(Config {
+            call_opcode_mask,
+            ret_opcode_mask,
+            call_operand_layout,
+            ..Config::new(instr_len, 1)?
+        })
+    }
+
+    /// Config for another instruction length, the call opcode keeps its length in the most significant bits and
+    /// the operand is the rest of the instruction, pc_inc follows the
+    /// instruction length. Only for configs made from a call opcode length.
+    pub fn with_instr_len(&self, instr_len: u64) -> Result<Config, String> {
+        let (call_opcode_mask, call_operand_layout, ret_opcode_mask) =
+            masks_from_call_opcode_len(instr_len, self.call_opcode_mask.count_ones() as u64)?;
+
+        Ok(Config {
+            instr_len,
+            infer_instr_len: false,
+            call_opcode_mask,
+            ret_opcode_mask,
+            call_operand_layout,
+            pc_inc: instr_len.div_ceil(BYTE_SIZE),
+            ..self.clone()
+        })
+    }
+
+    /// Config with the call opcode in the `call_opcode_len` most significant bits and the operand in the rest
+    pub fn with_call_opcode_len(&self, call_opcode_len: u64) -> Result<Config, String> {
+        let (call_opcode_mask, call_operand_layout, ret_opcode_mask) =
+            masks_from_call_opcode_len(self.instr_len, call_opcode_len)?;
+
+        Ok(Config {
+            call_opcode_mask,
+            call_opcode_len_search: None,
+            ret_opcode_mask,
+            call_operand_layout,
+            ..self.clone()
+        })
+    }
+
+    /// Address of the first analysed byte, the default is right for some Linux MIPS executables
+    pub fn pc_offset(&self) -> u64 {
+        self.pc_offset.unwrap_or(0x400000)
+    }
+
+    /// Bytes occupied by an instruction, instructions that are not a multiple of 8 bits are stored in the smallest
+    /// container of whole bytes
+    pub fn instr_byte_len(&self) -> usize {
+        self.instr_len.div_ceil(BYTE_SIZE) as usize
+    }
+
+    /// Distance between the addresses of consecutive instructions. Bit packed instructions do not start on byte
+    /// boundaries, thus they are addressed in instruction units.
+    pub fn instr_addr_len(&self) -> usize {
+        if self.bit_packed && self.length_rule.is_none() {
+            1
+        } else {
+            self.instr_byte_len()
+        }
+    }
+
+    /// Size of the word used by word addressing, the parcel for variable length instructions. Bit packed
+    /// instructions are already addressed in instruction units.
+    pub fn word_addr_len(&self) -> usize {
+        match &self.length_rule {
+            Some(length_rule) => (length_rule.parcel_len / BYTE_SIZE) as usize,
+            None => self.instr_addr_len(),
+        }
+    }
+
+    /// Number of addressable units in the binary, see `instr_addr_len`
+    pub fn address_space_len(&self, binary: &[u8]) -> usize {
+        if self.bit_packed && self.length_rule.is_none() {
+            binary.len() * BYTE_SIZE as usize / self.instr_len as usize
+        } else {
+            binary.len()
+        }
+    }
+
+    /// Config for the parameters of the analysis
+    pub fn from_parameters(parameters: crate::cli::cli_clap::AnalysisParameters) -> Config {
         // Deconstruct so that if adding more fields we get an error
-        let crate::cli::cli_clap::Parameters {
+        let crate::cli::cli_clap::AnalysisParameters {
             file_path,
             call_opcode_len,
+            call_opcode_len_range,
             instr_len,
+            bit_packed,
+            length_pattern,
+            parcel_len,
+            swap_parcels,
+            infer_length_rule,
             endiannes,
             addressing_mode,
             ret_opcode_index,
             call_opcode_index,
             call_operand_index,
+            call_operand_bits,
+            search_call_operand_layout,
             file_offset,
+            memory_map,
+            detect_code_regions,
+            code_region_window,
             pc_offset,
+            infer_pc_offset,
+            pc_offset_align,
+            region_size,
             pc_inc,
+            pc_bias,
             left_shift_call_operand,
             nr_cand,
             call_search_range,
             ret_search_range,
             ret_func_dist,
+            scoring,
             parallell,
             include_instructions,
-        } = crate::cli::cli_clap::parse_parameters();
+            output_format,
+            call_graph,
+            call_graph_format,
+        } = parameters;
+
+        // clap guarantees the file is given unless another subcommand replaces the analysis
+        let file_path = file_path.unwrap();
+
+        // Without -c or bit indexes the call opcode length is searched for
+        let call_opcode_len_search = if call_opcode_len.is_none() && call_opcode_index.is_none() {
+            if call_operand_bits.is_some() || call_opcode_len_range[0] > call_opcode_len_range[1] {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "searching for the call opcode length requires an increasing range and does not support \
+                        --call-operand-bits, got {:?}\n",
+                        call_opcode_len_range
+                    ),
+                )
+                .exit()
+            }
+            Some([call_opcode_len_range[0], call_opcode_len_range[1]])
+        } else {
+            None
+        };
+
+        // Detection only covers fixed width instructions split at the call opcode, the masks are made for the
+        // widest instruction and remade for each detected width
+        let infer_instr_len = instr_len.is_none();
+        if infer_instr_len
+            && (call_opcode_index.is_some()
+                || call_operand_bits.is_some()
+                || bit_packed
+                || !length_pattern.is_empty()
+                || infer_length_rule
+                || infer_pc_offset)
+        {
+            clap::Error::raw(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "instruction length detection does not support --call-opcode-index, --call-operand-bits, \
+                --bit-packed, --length-pattern, --infer-length-rule or --infer-pc-offset, give -i\n",
+            )
+            .exit()
+        }
+        let instr_len = instr_len.unwrap_or(u64::BITS as u64);
+
+        // TODO: Handle parsing of parameters, i.e file_offset cannot be greater than file length
+        if !(2..=64).contains(&instr_len) {
+            clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "instruction length must be between 2 and 64, got {}\n",
+                    instr_len
+                ),
+            )
+            .exit()
+        }
 
-        // TODO: Handle parsing of parameters, i.e file_offset cannot be greater than file length,
-        // instr_len only 8,16,32,64. indexes must be valid etc etc.
+        if infer_pc_offset {
+            if pc_offset_align == 0 {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    "pc offset alignment must be positive\n",
+                )
+                .exit()
+            }
+            if let AddressingMode::Relative
+            | AddressingMode::RelativeWord
+            | AddressingMode::Region
+            | AddressingMode::Page = addressing_mode
+            {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "pc offset inference requires an absolute or unknown addressing mode\n",
+                )
+                .exit()
+            }
+        }
+
+        if code_region_window == 0 {
+            clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                "code region window must be positive\n",
+            )
+            .exit()
+        }
+
+        if !region_size.is_power_of_two() {
+            clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "region size must be a power of two, got {:#x}\n",
+                    region_size
+                ),
+            )
+            .exit()
+        }
 
-        if call_opcode_len.is_none() {
-            println!(
-                "USE {:?} {:?} {:?}",
-                call_opcode_index, call_operand_index, ret_opcode_index
-            );
-            unimplemented!("Need to handle bitmasks when the additional parameters are provided");
+        let memory_map = memory_map.map(|path| {
+            std::fs::read_to_string(&path)
+                .map_err(|error| format!("could not read memory map {}: {}", path.display(), error))
+                .and_then(|text| text.parse::<MemoryMap>())
+                .unwrap_or_else(|error| {
+                    clap::Error::raw(clap::error::ErrorKind::ValueValidation, error + "\n").exit()
+                })
+        });
+
+        let pc_bias = match pc_bias.as_str() {
+            "unknown" => None,
+            value => Some(value.parse().unwrap_or_else(|_| {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("pc bias must be an integer or unknown, got {}\n", value),
+                )
+                .exit()
+            })),
+        };
+
+        let left_shift_call_operand = match left_shift_call_operand.as_str() {
+            "unknown" => None,
+            value => Some(value.parse().unwrap_or_else(|_| {
+                clap::Error::raw(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "left shift of call operand must be an integer or unknown, got {}\n",
+                        value
+                    ),
+                )
+                .exit()
+            })),
         };
-        let col = call_opcode_len.unwrap();
-        let temp = u64::MAX; //, need to handle overflow, do u64::max if 64 instr_len?
-        let call_opcode_mask: u64 = (temp) ^ ((1 << (instr_len - col)) - 1);
-        let call_operand_mask: u64 = (1 << (instr_len - col)) - 1;
-        let ret_opcode_mask: u64 = temp;
-        let call_operand_signed_mask: u64 = (1 << (instr_len - col - 1)) - 1;
-
-        let file_offset: [usize; 2] = if let Some(value) = file_offset {
-            value.try_into().unwrap()
+
+        let masks = if let Some(call_opcode_len) = call_opcode_len {
+            masks_from_call_opcode_len(instr_len, call_opcode_len)
+        } else if let Some([min_call_opcode_len, _]) = call_opcode_len_search {
+            masks_from_call_opcode_len(instr_len, min_call_opcode_len)
         } else {
-            [0, read_file_len(&file_path)]
+            // clap guarantees these are present when -c is omitted
+            masks_from_bit_ranges(
+                instr_len,
+                call_opcode_index.unwrap().try_into().unwrap(),
+                call_operand_index.unwrap().try_into().unwrap(),
+                ret_opcode_index.map(|value| value.try_into().unwrap()),
+            )
+        };
+        let masks = masks.and_then(|(call_opcode_mask, call_operand_layout, ret_opcode_mask)| {
+            // Scattered operand bits replace the contiguous operand
+            let call_operand_layout = match call_operand_bits {
+                Some(bits) => with_call_operand_bits(instr_len, call_opcode_mask, &bits)?,
+                None => call_operand_layout,
+            };
+            Ok((call_opcode_mask, call_operand_layout, ret_opcode_mask))
+        });
+        let (call_opcode_mask, call_operand_layout, ret_opcode_mask) =
+            masks.unwrap_or_else(|error| {
+                clap::Error::raw(clap::error::ErrorKind::ValueValidation, error + "\n").exit()
+            });
+
+        // With no patterns the rule still carries the parcel settings for inference
+        let length_rule = if length_pattern.is_empty() && !infer_length_rule {
+            None
+        } else {
+            Some(
+                LengthRule::new(parcel_len, length_pattern, swap_parcels).unwrap_or_else(|error| {
+                    clap::Error::raw(clap::error::ErrorKind::ValueValidation, error + "\n").exit()
+                }),
+            )
         };
 
         Config {
             file_path,
             instr_len,
+            bit_packed,
+            length_rule,
+            infer_length_rule,
+            infer_instr_len,
             call_opcode_mask,
+            call_opcode_len_search,
             ret_opcode_mask,
-            call_operand_mask,
-            call_operand_signed_mask,
+            call_operand_layout,
+            call_operand_layout_search: search_call_operand_layout,
             endiannes,
             addressing_mode,
-            file_offset,
+            file_offset: file_offset.map(|value| value.try_into().unwrap()),
+            memory_map,
+            detect_code_regions,
+            code_region_window,
             pc_offset,
+            infer_pc_offset,
+            pc_offset_align,
+            region_size,
             pc_inc: if let Some(value) = pc_inc {
                 value
+            } else if bit_packed {
+                1
             } else {
-                instr_len / BYTE_SIZE
+                instr_len.div_ceil(BYTE_SIZE)
             },
+            pc_bias,
             left_shift_call_operand,
             nr_cand,
             call_search_range: call_search_range.try_into().unwrap(),
             ret_search_range: ret_search_range.try_into().unwrap(),
             ret_func_dist,
+            scorer: scoring.scorer(),
             parallell,
             include_instructions,
+            output_format,
+            call_graph,
+            call_graph_format,
         }
     }
 }
+
+// Returns (call_opcode_mask, call_operand_layout, ret_opcode_mask)
+fn masks_from_call_opcode_len(
+    instr_len: u64,
+    call_opcode_len: u64,
+) -> Result<(u64, OperandLayout, u64), String> {
+    if call_opcode_len == 0 || call_opcode_len >= instr_len {
+        return Err(format!(
+            "call opcode length must be between 1 and {}, got {}",
+            instr_len - 1,
+            call_opcode_len
+        ));
+    }
+
+    masks_from_bit_ranges(
+        instr_len,
+        [instr_len - call_opcode_len, instr_len - 1],
+        [0, instr_len - call_opcode_len - 1],
+        None,
+    )
+}
+
+// Returns (call_opcode_mask, call_operand_layout, ret_opcode_mask)
+fn masks_from_bit_ranges(
+    instr_len: u64,
+    call_opcode_index: [u64; 2],
+    call_operand_index: [u64; 2],
+    ret_opcode_index: Option<[u64; 2]>,
+) -> Result<(u64, OperandLayout, u64), String> {
+    let call_opcode_mask = bit_range_mask(instr_len, call_opcode_index, "call opcode")?;
+    let call_operand_mask = bit_range_mask(instr_len, call_operand_index, "call operand")?;
+    let ret_opcode_mask = match ret_opcode_index {
+        Some(ret_opcode_index) => bit_range_mask(instr_len, ret_opcode_index, "ret opcode")?,
+        None => bit_range_mask(instr_len, [0, instr_len - 1], "ret opcode")?,
+    };
+
+    if call_opcode_mask & call_operand_mask != 0 {
+        return Err(format!(
+            "call opcode bits {:?} and call operand bits {:?} overlap",
+            call_opcode_index, call_operand_index
+        ));
+    }
+
+    Ok((
+        call_opcode_mask,
+        OperandLayout::contiguous(call_operand_mask),
+        ret_opcode_mask,
+    ))
+}
+
+fn with_call_operand_bits(
+    instr_len: u64,
+    call_opcode_mask: u64,
+    call_operand_bits: &str,
+) -> Result<OperandLayout, String> {
+    let layout = OperandLayout::new(instr_len, parse_bit_ranges(call_operand_bits)?)?;
+    if layout.mask() & call_opcode_mask != 0 {
+        return Err(format!(
+            "call operand bits {} overlap with the call opcode",
+            layout
+        ));
+    }
+    Ok(layout)
+}
+
+// Mask with the bits in the inclusive range set, the order of the two indexes does not matter
+fn bit_range_mask(instr_len: u64, index: [u64; 2], name: &str) -> Result<u64, String> {
+    let (low, high) = (index[0].min(index[1]), index[0].max(index[1]));
+    if high >= instr_len {
+        return Err(format!(
+            "{} index {:?} is out of range for instruction length {}",
+            name, index, instr_len
+        ));
+    }
+
+    Ok((u64::MAX >> (u64::BITS as u64 - 1 - high)) & (u64::MAX << low))
+}
@@ -0,0 +1,9 @@
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    // Graphviz
+    Dot,
+    // I.e for Gephi
+    Graphml,
+}
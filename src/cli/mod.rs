@@ -2,3 +2,6 @@ pub mod addressing_mode;
 pub mod cli_clap;
 pub mod config;
 pub mod endiannes;
+pub mod graph_format;
+pub mod output_format;
+pub mod scoring;
@@ -0,0 +1,10 @@
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    // A single JSON document, see `crate::report`
+    Json,
+    // One JSON object per line, see `crate::report`
+    JsonLines,
+}
@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+
+use crate::scorer::{EdgeScorer, Scorer, TargetScorer};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scoring {
+    // See `EdgeScorer`
+    Edges,
+    // See `TargetScorer`
+    Targets,
+}
+
+impl Scoring {
+    pub fn scorer(&self) -> Arc<dyn Scorer> {
+        match self {
+            Scoring::Edges => Arc::new(EdgeScorer),
+            Scoring::Targets => Arc::new(TargetScorer),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use rustc_hash::FxHashMap;
+
+use crate::candidates_opcodes::call_opcode_histogram;
+use crate::instr_len::{byte_histogram, entropy};
+use crate::memory_map::{MemoryMap, Region};
+use crate::prelude::*;
+
+// Padding and tables have a lower byte entropy than code, compressed and encrypted data a higher one. In bits.
+const MIN_ENTROPY: f64 = 3.0;
+const MAX_ENTROPY: f64 = 7.5;
+// Least similarity of the opcode histogram of a window to the one of the whole range
+const MIN_SIMILARITY: f64 = 0.7;
+
+/// Part of the binary proposed as code by `detect_code_regions`, with the mean statistics of its windows
+#[derive(Clone, Copy)]
+pub struct CodeRegion {
+    pub file_offset: [usize; 2],
+    // Byte entropy in bits
+    pub entropy: f64,
+    // Cosine similarity of the call opcode histogram to the one of the whole range
+    pub similarity: f64,
+}
+
+/// Proposes the parts of the analysed range that look like code. The range is split into windows of
+/// `config.code_region_window` bytes, and a window is code if
+/// - its byte entropy is between 3 and 7.5 bits, which rules out padding and compressed or encrypted data
+/// - its call opcode histogram (see `call_opcode_histogram`) is similar to the one of all windows with such an
+///   entropy, which rules out strings and tables as long as most of them are code
+///
+/// Consecutive code windows are joined, a trailing window shorter than half the window length is dropped.
+/// Returns the regions in file order.
+pub fn detect_code_regions(binary: &[u8], config: &Config) -> Vec<CodeRegion> {
+    let [start, end] = config.file_offset.unwrap_or([0, binary.len()]);
+    let window_len = config.code_region_window;
+    // Windows are only compared with each other, thus any endiannes does when it is unknown
+    let endiannes = match config.endiannes {
+        Endiannes::Unknown => Endiannes::Big,
+        endiannes => endiannes,
+    };
+
+    let windows: Vec<([usize; 2], f64, FxHashMap<u64, usize>)> = (start..end)
+        .step_by(window_len)
+        .map(|window_start| [window_start, (window_start + window_len).min(end)])
+        .filter(|&[window_start, window_end]| 2 * (window_end - window_start) >= window_len)
+        .map(|[window_start, window_end]| {
+            let window = &binary[window_start..window_end];
+            (
+                [window_start, window_end],
+                entropy(&byte_histogram(window.iter())),
+                call_opcode_histogram(window, config, &endiannes),
+            )
+        })
+        .collect();
+
+    let mut reference: FxHashMap<u64, usize> = Default::default();
+    for (_, _, histogram) in windows
+        .iter()
+        .filter(|(_, entropy, _)| (MIN_ENTROPY..=MAX_ENTROPY).contains(entropy))
+    {
+        for (&opcode, &count) in histogram {
+            *reference.entry(opcode).or_insert(0) += count;
+        }
+    }
+
+    let mut regions: Vec<CodeRegion> = Vec::new();
+    // Number of windows joined into the last region, 0 once a window that is not code follows it
+    let mut joined = 0;
+    for (file_offset, entropy, histogram) in windows {
+        let similarity = cosine_similarity(&histogram, &reference);
+        if !(MIN_ENTROPY..=MAX_ENTROPY).contains(&entropy) || similarity < MIN_SIMILARITY {
+            joined = 0;
+            continue;
+        }
+
+        match regions.last_mut() {
+            Some(region) if joined > 0 => {
+                region.file_offset[1] = file_offset[1];
+                region.entropy = (region.entropy * joined as f64 + entropy) / (joined + 1) as f64;
+                region.similarity =
+                    (region.similarity * joined as f64 + similarity) / (joined + 1) as f64;
+                joined += 1;
+            }
+            _ => {
+                regions.push(CodeRegion {
+                    file_offset,
+                    entropy,
+                    similarity,
+                });
+                joined = 1;
+            }
+        }
+    }
+    regions
+}
+
+/// Memory map of the regions, loaded as they are in the analysed range at `pc_offset`. None if there are no regions.
+pub fn code_region_map(regions: &[CodeRegion], config: &Config) -> Option<MemoryMap> {
+    let [start, _] = config.file_offset.unwrap_or([0, 0]);
+    let regions = regions
+        .iter()
+        .map(|region| Region {
+            file_offset: region.file_offset,
+            address: config.pc_offset() + (region.file_offset[0] - start) as u64,
+            read: true,
+            write: false,
+            execute: true,
+        })
+        .collect();
+    MemoryMap::new(regions).ok()
+}
+
+fn cosine_similarity(a: &FxHashMap<u64, usize>, b: &FxHashMap<u64, usize>) -> f64 {
+    let norm = |histogram: &FxHashMap<u64, usize>| {
+        histogram
+            .values()
+            .map(|&count| (count as f64).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    };
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(opcode, &count)| b.get(opcode).map(|&other| count as f64 * other as f64))
+        .sum();
+
+    match norm(a) * norm(b) {
+        norms if norms > 0.0 => dot / norms,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_between_random_data_is_detected() {
+        let mut seed = 0x2545f491u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        // Functions of a few MIPS instructions that differ in their operands
+        let code: Vec<u8> = (0..0xc00u32)
+            .flat_map(|index| {
+                match index % 6 {
+                    0 => 0x27bdffe8,
+                    1 => 0xafbf0014,
+                    2 => 0x0c100000 | (index & 0x3ff),
+                    3 => 0x24020000 | (index & 0xff),
+                    4 => 0x03e00008,
+                    _ => 0x27bd0018,
+                }
+                .to_be_bytes()
+            })
+            .collect();
+        let mut binary: Vec<u8> = (0..0x2000).map(|_| random() as u8).collect();
+        binary.extend_from_slice(&code);
+        binary.extend((0..0x2000).map(|_| random() as u8));
+
+        let config = Config {
+            endiannes: Endiannes::Big,
+            pc_offset: Some(0x80000000),
+            ..Config::new(32, 6).unwrap()
+        };
+        let regions = detect_code_regions(&binary, &config);
+        let file_offsets: Vec<[usize; 2]> =
+            regions.iter().map(|region| region.file_offset).collect();
+        assert_eq!(file_offsets, vec![[0x2000, 0x5000]]);
+        assert!((MIN_ENTROPY..=MAX_ENTROPY).contains(&regions[0].entropy));
+
+        let memory_map = code_region_map(&regions, &config).unwrap();
+        assert_eq!(
+            memory_map.code_regions().next().unwrap().address,
+            0x80002000
+        );
+    }
+}
@@ -1,78 +1,166 @@
+use crate::address_space::AddressSpace;
 use crate::prelude::*;
-use std::ops::Neg;
+use itertools::Either;
 
-use crate::iter_instructions::{iter_instructions, iter_instructions_and_search};
+use crate::iter_instructions::{
+    iter_instruction_stream, iter_instructions, iter_instructions_and_search,
+    iter_packed_instructions, read_packed_instruction,
+};
 
 // TODO have call edges struct
 pub fn find_potential_edges(
     binary: &[u8],
     call_candidate: u64,
     config: &Config,
-    endiannes: &Endiannes,
-    addressing_mode: &AddressingMode,
+    instruction_configuration: &InstructionConfiguration,
+    call_operand_layout: &OperandLayout,
+) -> Vec<(usize, usize)> {
+    let endiannes = &instruction_configuration.endiannes;
+    let instr_byte_len = config.instr_byte_len();
+
+    // Seperate monomorphized calls for fixed and variable length, keeps the fixed length hot path as fast as before
+    let mut potential_edges = match &config.length_rule {
+        Some(length_rule) => collect_potential_edges(
+            iter_instruction_stream(binary, endiannes, length_rule)
+                .filter(|&(_, instr_len, _)| instr_len == config.instr_len)
+                .map(|(offset, _, instr)| (offset, instr)),
+            binary,
+            call_candidate,
+            config,
+            instruction_configuration,
+            call_operand_layout,
+        ),
+        // Bit packed instructions are addressed by their index
+        None if config.bit_packed => collect_potential_edges(
+            iter_packed_instructions(binary, endiannes, config.instr_len).enumerate(),
+            binary,
+            call_candidate,
+            config,
+            instruction_configuration,
+            call_operand_layout,
+        ),
+        None => collect_potential_edges(
+            iter_instructions(binary, endiannes, config.instr_len)
+                .enumerate()
+                .map(|(i, instr)| (i * instr_byte_len, instr)),
+            binary,
+            call_candidate,
+            config,
+            instruction_configuration,
+            call_operand_layout,
+        ),
+    };
+
+    // Performance improvement:
+    // sorted makes memory access in for loop in filter_valid_edges much more efficient, reducing runtime by ̃~15%
+    // This also seems to be more efficient than using a binary heap, most likely because most lists are small
+    potential_edges.sort_unstable_by_key(|&(_, to)| to);
+    potential_edges
+}
+
+// Takes (address, instruction) pairs, see `Config::instr_addr_len`
+fn collect_potential_edges(
+    instructions: impl Iterator<Item = (usize, u64)>,
+    binary: &[u8],
+    call_candidate: u64,
+    config: &Config,
+    instruction_configuration: &InstructionConfiguration,
+    call_operand_layout: &OperandLayout,
 ) -> Vec<(usize, usize)> {
     // Destructure CLI params we need
     let &Config {
         call_opcode_mask,
-        call_operand_mask,
-        call_operand_signed_mask,
-        pc_offset,
-        left_shift_call_operand,
+        region_size,
         ..
     } = config;
+    let &InstructionConfiguration {
+        ref addressing_mode,
+        pc_bias,
+        left_shift_call_operand,
+        ..
+    } = instruction_configuration;
 
-    let call_operand_signed_mask = call_operand_signed_mask as i64;
+    let instr_addr_len = config.instr_addr_len();
+    let region_mask = region_size as i64 - 1;
+    let address_space = AddressSpace::new(binary, config, instruction_configuration);
     let mut potential_edges = Vec::new();
 
-    if let AddressingMode::Absolute = addressing_mode {
-        for (i, instr) in iter_instructions(binary, endiannes, config.instr_len).enumerate() {
-            if instr & call_opcode_mask == call_candidate {
-                let call_operand = instr & call_operand_mask;
-                let address = ((call_operand as i64) << left_shift_call_operand) - pc_offset as i64;
-                if binary.get(address as usize).is_some() {
-                    potential_edges.push((
-                        i * (config.instr_len / BYTE_SIZE) as usize,
-                        address as usize,
-                    ));
-                }
-            }
+    // Word addressed operands are converted to byte offsets
+    let address_scale = match addressing_mode {
+        AddressingMode::AbsoluteWord | AddressingMode::RelativeWord => {
+            config.word_addr_len() as i64
         }
-    } else if let AddressingMode::Relative = addressing_mode {
-        for (i, instr) in iter_instructions(binary, endiannes, config.instr_len).enumerate() {
-            if instr & call_opcode_mask == call_candidate {
-                let call_operand = (instr & call_operand_mask) as i64;
-                let signed_operand = {
-                    if call_operand > call_operand_signed_mask {
-                        call_operand | call_operand_signed_mask.neg()
-                    } else {
-                        call_operand
-                    }
-                };
-                // Maybe i.checked_add(rest) ?? Because we know the result should be unsigned
+        _ => 1,
+    };
+
+    for (offset, instr) in instructions {
+        if instr & call_opcode_mask != call_candidate {
+            continue;
+        }
+        // Only padding between the regions of a memory map has no address
+        let Some(caller) = address_space.address(offset) else {
+            continue;
+        };
+        let caller = caller as i64;
+
+        // Targets that do not fit, i.e 64 bit operands shifted and scaled, are skipped like those outside the slice
+        let target = match addressing_mode {
+            AddressingMode::Absolute | AddressingMode::AbsoluteWord => scale_operand(
+                call_operand_layout.extract(instr) as i64,
+                left_shift_call_operand,
+                address_scale,
+            ),
+            AddressingMode::Relative | AddressingMode::RelativeWord => {
+                // Operand bits are reassembled before sign extension
+                let signed_operand = call_operand_layout.extract_signed(instr);
 
                 // TODO, maybe pc_inc is really uneccesarry? it's really always instr_len / byte size, in all archs
                 // TODO change all usizes to u64 because usize doesn't work on 64bit
 
                 // Assume that all ISA left-shifts by byte length of instruction, thus we do not need to check
                 // that address % instr_byte_len == 0, since it would be valid for all
-                let address = (signed_operand << left_shift_call_operand)
-                    + (i as u64 * (config.instr_len / BYTE_SIZE)) as i64;
-                if binary.get(address as usize).is_some()
-                    && (i as i64 - address).abs() > 4 * (config.instr_len / BYTE_SIZE) as i64
-                {
-                    potential_edges.push((
-                        i * (config.instr_len / BYTE_SIZE) as usize,
-                        address as usize,
-                    ));
+                let target = scale_operand(signed_operand, left_shift_call_operand, address_scale)
+                    .and_then(|displacement| displacement.checked_add(caller))
+                    .and_then(|target| target.checked_add(pc_bias));
+                match target {
+                    Some(target) if caller.abs_diff(target) <= 4 * instr_addr_len as u64 => {
+                        continue
+                    }
+                    target => target,
                 }
             }
+            AddressingMode::Region | AddressingMode::Page => {
+                // The region of the caller, plus the bias
+                caller.checked_add(pc_bias).and_then(|address| {
+                    let region = address & !region_mask;
+                    if let AddressingMode::Region = addressing_mode {
+                        let call_operand = call_operand_layout.extract(instr) as i64;
+                        Some(
+                            region
+                                | (call_operand.checked_shl(left_shift_call_operand as u32)?
+                                    & region_mask),
+                        )
+                    } else {
+                        let displacement = scale_operand(
+                            call_operand_layout.extract_signed(instr),
+                            left_shift_call_operand,
+                            1,
+                        )?;
+                        region.checked_add(displacement)
+                    }
+                })
+            }
+            AddressingMode::Unknown => continue,
+        };
+
+        if let Some(target) = target
+            .and_then(|target| u64::try_from(target).ok())
+            .and_then(|target| address_space.slice_offset(target))
+        {
+            potential_edges.push((offset, target));
         }
     }
 
-    // Performance improvement:
-    // sorted makes memory access in for loop in filter_valid_edges much more efficient, reducing runtime by ̃~15%
-    // This also seems to be more efficient than using a binary heap, most likely because most lists are small
-    potential_edges.sort_unstable_by_key(|&(_, to)| to);
     potential_edges
 }
 
@@ -89,8 +177,36 @@ pub fn filter_valid_edges(
         ..
     } = config;
 
+    if let Some(length_rule) = &config.length_rule {
+        let instructions: Vec<_> =
+            iter_instruction_stream(binary, endiannes, length_rule).collect();
+        return filter_valid_edges_in_stream(
+            ret_opcode,
+            config,
+            potential_call_edges,
+            &instructions,
+        );
+    }
+
     let mut valid_call_edges: Vec<(usize, usize)> = Vec::new();
-    let distance = (ret_func_dist) * (config.instr_len / BYTE_SIZE) as usize;
+
+    if config.bit_packed {
+        for &(from_edge, to_edge) in potential_call_edges {
+            // if (is first function) || (function has preceding return)
+            if to_edge <= ret_func_dist
+                || (to_edge - ret_func_dist..to_edge).any(|index| {
+                    read_packed_instruction(binary, endiannes, config.instr_len, index)
+                        & ret_opcode_mask
+                        == ret_opcode
+                })
+            {
+                valid_call_edges.push((from_edge, to_edge));
+            }
+        }
+        return valid_call_edges;
+    }
+
+    let distance = (ret_func_dist) * config.instr_byte_len();
 
     for &(from_edge, to_edge) in potential_call_edges {
         // The first function in the program has no preceding return statement, thus we mark it valid either way.
@@ -112,3 +228,136 @@ pub fn filter_valid_edges(
     }
     valid_call_edges
 }
+
+// The operand shifted and scaled to an address difference, None if it does not fit
+fn scale_operand(operand: i64, left_shift_call_operand: u64, address_scale: i64) -> Option<i64> {
+    let factor = 1i64.checked_shl(u32::try_from(left_shift_call_operand).ok()?)?;
+    operand.checked_mul(factor)?.checked_mul(address_scale)
+}
+
+/// `filter_valid_edges` for variable length instructions, where the stream from `iter_instruction_stream` is decoded
+/// up front since instructions can not be decoded backwards from the call target.
+pub fn filter_valid_edges_in_stream(
+    ret_opcode: u64,
+    config: &Config,
+    potential_call_edges: &Vec<(usize, usize)>,
+    instructions: &[(usize, u64, u64)],
+) -> Vec<(usize, usize)> {
+    let &Config {
+        ret_func_dist,
+        ret_opcode_mask,
+        ..
+    } = config;
+
+    let mut valid_call_edges: Vec<(usize, usize)> = Vec::new();
+
+    for &(from_edge, to_edge) in potential_call_edges {
+        if let Some(index) = instruction_index(instructions, to_edge, |&(offset, _, _)| offset) {
+            // if (is first function) || (function has preceding return)
+            if index <= ret_func_dist
+                || instructions[index - ret_func_dist..index]
+                    .iter()
+                    .any(|&(_, _, instr)| instr & ret_opcode_mask == ret_opcode)
+            {
+                valid_call_edges.push((from_edge, to_edge));
+            }
+        }
+    }
+    valid_call_edges
+}
+
+/// Index of the instruction starting at the offset in instructions sorted by offset. None if no instruction starts
+/// there, a target that is not the start of an instruction can not be a function, i.e unaligned relative targets.
+pub(crate) fn instruction_index<T>(
+    instructions: &[T],
+    offset: usize,
+    instruction_offset: impl FnMut(&T) -> usize,
+) -> Option<usize> {
+    instructions
+        .binary_search_by_key(&offset, instruction_offset)
+        .ok()
+}
+
+/// Targets of the absolute calls in operand units, that is before subtracting `pc_offset` and scaling to a byte
+/// address. Used to infer `pc_offset`, see `Analysis::infer_pc_offset`.
+pub fn absolute_call_targets(
+    binary: &[u8],
+    call_candidate: u64,
+    config: &Config,
+    instruction_configuration: &InstructionConfiguration,
+    call_operand_layout: &OperandLayout,
+) -> Vec<u64> {
+    let endiannes = &instruction_configuration.endiannes;
+    let left_shift_call_operand = instruction_configuration.left_shift_call_operand;
+    let target = |instr: u64| {
+        (instr & config.call_opcode_mask == call_candidate)
+            .then(|| call_operand_layout.extract(instr) << left_shift_call_operand)
+    };
+
+    match &config.length_rule {
+        Some(length_rule) => iter_instruction_stream(binary, endiannes, length_rule)
+            .filter(|&(_, instr_len, _)| instr_len == config.instr_len)
+            .filter_map(|(_, _, instr)| target(instr))
+            .collect(),
+        None if config.bit_packed => iter_packed_instructions(binary, endiannes, config.instr_len)
+            .filter_map(target)
+            .collect(),
+        None => iter_instructions(binary, endiannes, config.instr_len)
+            .filter_map(target)
+            .collect(),
+    }
+}
+
+/// Addresses that have a return among the `ret_func_dist` preceding instructions, that is the call targets
+/// `filter_valid_edges` accepts. Sorted and without duplicates.
+pub fn function_starts(
+    binary: &[u8],
+    ret_opcode: u64,
+    config: &Config,
+    endiannes: &Endiannes,
+) -> Vec<usize> {
+    let &Config {
+        ret_func_dist,
+        ret_opcode_mask,
+        ..
+    } = config;
+
+    let mut function_starts = Vec::new();
+    if let Some(length_rule) = &config.length_rule {
+        let instructions: Vec<_> =
+            iter_instruction_stream(binary, endiannes, length_rule).collect();
+        for (index, &(_, _, instr)) in instructions.iter().enumerate() {
+            if instr & ret_opcode_mask == ret_opcode {
+                function_starts.extend(
+                    instructions[index + 1..]
+                        .iter()
+                        .take(ret_func_dist)
+                        .map(|&(offset, _, _)| offset),
+                );
+            }
+        }
+    } else {
+        let instr_addr_len = config.instr_addr_len();
+        let instructions = if config.bit_packed {
+            Either::Left(iter_packed_instructions(
+                binary,
+                endiannes,
+                config.instr_len,
+            ))
+        } else {
+            Either::Right(iter_instructions(binary, endiannes, config.instr_len))
+        };
+        for (index, instr) in instructions.enumerate() {
+            if instr & ret_opcode_mask == ret_opcode {
+                function_starts
+                    .extend((1..=ret_func_dist).map(|dist| (index + dist) * instr_addr_len));
+            }
+        }
+    }
+
+    let address_space_len = config.address_space_len(binary);
+    function_starts.retain(|&address| address < address_space_len);
+    function_starts.sort_unstable();
+    function_starts.dedup();
+    function_starts
+}
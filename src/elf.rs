@@ -0,0 +1,260 @@
+use crate::file::{read_word, Container, Section};
+use crate::prelude::*;
+
+const PT_LOAD: u64 = 1;
+const PF_X: u64 = 1;
+const SHT_PROGBITS: u64 = 1;
+const SHF_EXECINSTR: u64 = 4;
+
+/// Reads the ELF header, whatever the machine. Code is taken from the executable sections, or the executable
+/// segments if there are no section headers. None if the binary is not an ELF or the headers are truncated.
+pub fn parse_elf(binary: &[u8]) -> Option<Container> {
+    if !binary.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let word_len = match binary.get(4)? {
+        1 => 32,
+        2 => 64,
+        _ => return None,
+    };
+    let endiannes = match binary.get(5)? {
+        1 => Endiannes::Little,
+        2 => Endiannes::Big,
+        _ => return None,
+    };
+    let read = |offset: usize, len: usize| read_word(binary, &endiannes, offset, len);
+
+    // The table offsets differ between 32 and 64 bit headers, they are followed by e_flags, e_ehsize and then
+    // e_phentsize, e_phnum, e_shentsize and e_shnum
+    let (phoff, shoff, sizes) = if word_len == 32 {
+        (read(0x1c, 4)?, read(0x20, 4)?, 0x2a)
+    } else {
+        (read(0x20, 8)?, read(0x28, 8)?, 0x36)
+    };
+    let (phentsize, phnum) = (read(sizes, 2)?, read(sizes + 2, 2)?);
+    let (shentsize, shnum) = (read(sizes + 4, 2)?, read(sizes + 6, 2)?);
+
+    let mut segments = Vec::new();
+    let mut code_segments = Vec::new();
+    for index in 0..phnum {
+        let header = usize::try_from(phoff.checked_add(index * phentsize)?).ok()?;
+        if header > binary.len() {
+            return None;
+        }
+        let (p_type, p_flags, p_offset, p_vaddr, p_filesz) = if word_len == 32 {
+            (
+                read(header, 4)?,
+                read(header + 24, 4)?,
+                read(header + 4, 4)?,
+                read(header + 8, 4)?,
+                read(header + 16, 4)?,
+            )
+        } else {
+            (
+                read(header, 4)?,
+                read(header + 4, 4)?,
+                read(header + 8, 8)?,
+                read(header + 16, 8)?,
+                read(header + 32, 8)?,
+            )
+        };
+        if p_type == PT_LOAD && p_filesz > 0 {
+            let segment = Section {
+                file_offset: [
+                    p_offset as usize,
+                    p_offset.saturating_add(p_filesz) as usize,
+                ],
+                address: p_vaddr,
+            };
+            if p_flags & PF_X != 0 {
+                code_segments.push(segment);
+            }
+            segments.push(segment);
+        }
+    }
+
+    let mut code_sections = Vec::new();
+    for index in 0..shnum {
+        let header = usize::try_from(shoff.checked_add(index * shentsize)?).ok()?;
+        if header > binary.len() {
+            return None;
+        }
+        let (sh_type, sh_flags, sh_addr, sh_offset, sh_size) = if word_len == 32 {
+            (
+                read(header + 4, 4)?,
+                read(header + 8, 4)?,
+                read(header + 12, 4)?,
+                read(header + 16, 4)?,
+                read(header + 20, 4)?,
+            )
+        } else {
+            (
+                read(header + 4, 4)?,
+                read(header + 8, 8)?,
+                read(header + 16, 8)?,
+                read(header + 24, 8)?,
+                read(header + 32, 8)?,
+            )
+        };
+        if sh_type == SHT_PROGBITS && sh_flags & SHF_EXECINSTR != 0 && sh_size > 0 {
+            code_sections.push(Section {
+                file_offset: [
+                    sh_offset as usize,
+                    sh_offset.saturating_add(sh_size) as usize,
+                ],
+                address: sh_addr,
+            });
+        }
+    }
+
+    let code = if code_sections.is_empty() {
+        code_segments
+    } else {
+        code_sections
+    };
+    // Object files have no segments, their sections are mapped by their own addresses
+    if segments.is_empty() {
+        segments = code.clone();
+    }
+    if code
+        .iter()
+        .chain(segments.iter())
+        .any(|section| section.file_offset[1] > binary.len())
+    {
+        return None;
+    }
+
+    Some(Container {
+        endiannes,
+        word_len: Some(word_len),
+        code,
+        segments,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::file::tests::write_word;
+
+    const SHT_NOBITS: u64 = 8;
+
+    // 32 bit big endian executable with a single executable segment loaded at 0x400000
+    fn elf32() -> Vec<u8> {
+        let mut binary = vec![0; 0x64];
+        binary[..7].copy_from_slice(b"\x7fELF\x01\x02\x01");
+        let mut write = |offset: usize, len: usize, value: u64| {
+            write_word(&mut binary, &Endiannes::Big, offset, len, value)
+        };
+        // e_phoff, e_phentsize, e_phnum, no section headers
+        write(0x1c, 4, 0x34);
+        write(0x2a, 2, 0x20);
+        write(0x2c, 2, 1);
+        // PT_LOAD of the whole file, readable and executable
+        write(0x34, 4, PT_LOAD);
+        write(0x34 + 4, 4, 0);
+        write(0x34 + 8, 4, 0x400000);
+        write(0x34 + 16, 4, 0x64);
+        write(0x34 + 24, 4, PF_X | 0x4);
+        binary
+    }
+
+    #[test]
+    fn executable_segment_is_code() {
+        let container = parse_elf(&elf32()).unwrap();
+        assert!(matches!(container.endiannes, Endiannes::Big));
+        assert_eq!(container.word_len, Some(32));
+        let code = container.code_range().unwrap();
+        assert_eq!((code.file_offset, code.address), ([0, 0x64], 0x400000));
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        assert!(parse_elf(&elf32()[..0x40]).is_none());
+        assert!(parse_elf(&elf32()[..0x20]).is_none());
+    }
+
+    /// 64 bit little endian executable with section headers. .init at 0x400180 and .text at 0x400200 are loaded as
+    /// in the file, .boot at 0xbfc00000 is not, .data and .bss are not code.
+    pub(crate) fn elf64() -> Vec<u8> {
+        let mut binary = vec![0; 0x400];
+        binary[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        let mut write = |offset: usize, len: usize, value: u64| {
+            write_word(&mut binary, &Endiannes::Little, offset, len, value)
+        };
+        // e_phoff, e_shoff, e_phentsize, e_phnum, e_shentsize, e_shnum
+        write(0x20, 8, 0x40);
+        write(0x28, 8, 0x80);
+        write(0x36, 2, 0x38);
+        write(0x38, 2, 1);
+        write(0x3a, 2, 0x40);
+        write(0x3c, 2, 6);
+        // PT_LOAD of the whole file, readable and executable
+        write(0x40, 4, PT_LOAD);
+        write(0x40 + 4, 4, PF_X | 0x4);
+        write(0x40 + 8, 8, 0);
+        write(0x40 + 16, 8, 0x400000);
+        write(0x40 + 32, 8, 0x400);
+        // A null section header, then sh_type, sh_flags, sh_addr, sh_offset and sh_size of each section
+        let sections = [
+            (SHT_PROGBITS, SHF_EXECINSTR, 0x400180, 0x180, 0x40),
+            (SHT_PROGBITS, SHF_EXECINSTR, 0x400200, 0x200, 0x100),
+            (SHT_PROGBITS, SHF_EXECINSTR, 0xbfc00000, 0x300, 0x80),
+            (SHT_PROGBITS, 0x3, 0x10000000, 0x380, 0x80),
+            (SHT_NOBITS, 0x3, 0x10000080, 0x400, 0x1000),
+        ];
+        for (index, &(sh_type, sh_flags, sh_addr, sh_offset, sh_size)) in
+            sections.iter().enumerate()
+        {
+            let header = 0x80 + (index + 1) * 0x40;
+            write(header + 4, 4, sh_type);
+            write(header + 8, 8, sh_flags);
+            write(header + 16, 8, sh_addr);
+            write(header + 24, 8, sh_offset);
+            write(header + 32, 8, sh_size);
+        }
+        binary
+    }
+
+    #[test]
+    fn executable_sections_are_code() {
+        let container = parse_elf(&elf64()).unwrap();
+        assert!(matches!(container.endiannes, Endiannes::Little));
+        assert_eq!(container.word_len, Some(64));
+        let code: Vec<_> = container
+            .code
+            .iter()
+            .map(|section| (section.file_offset, section.address))
+            .collect();
+        assert_eq!(
+            code,
+            vec![
+                ([0x180, 0x1c0], 0x400180),
+                ([0x200, 0x300], 0x400200),
+                ([0x300, 0x380], 0xbfc00000)
+            ]
+        );
+        assert_eq!(container.address(0x380), Some(0x400380));
+    }
+
+    #[test]
+    fn code_sections_loaded_alike_are_joined() {
+        let ranges: Vec<_> = parse_elf(&elf64())
+            .unwrap()
+            .code_ranges()
+            .iter()
+            .map(|range| (range.file_offset, range.address))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![([0x180, 0x300], 0x400180), ([0x300, 0x380], 0xbfc00000)]
+        );
+    }
+
+    #[test]
+    fn sections_outside_the_file_are_rejected() {
+        let mut binary = elf64();
+        write_word(&mut binary, &Endiannes::Little, 0xc0 + 32, 8, 0x1000);
+        assert!(parse_elf(&binary).is_none());
+    }
+}
@@ -1,3 +1,8 @@
+use crate::elf::parse_elf;
+use crate::hex_records::parse_hex_records;
+use crate::macho::parse_macho;
+use crate::memory_map::Region;
+use crate::pe::parse_pe;
 use crate::prelude::*;
 
 pub fn read_file(config: &Config) -> Vec<u8> {
@@ -7,8 +12,277 @@ pub fn read_file(config: &Config) -> Vec<u8> {
     std::fs::read(file_path).expect("file not found")
 }
 
-pub fn read_file_len(file_path: &PathBuf) -> usize {
-    std::fs::metadata(file_path)
-        .expect("file does not exist")
-        .len() as usize
+/// Executable file format such as ELF, PE or Mach-O, or firmware records, see `parse_container`
+pub struct Container {
+    pub endiannes: Endiannes,
+    // Size of addresses, likely also the size of the instructions. Unknown for firmware records
+    pub word_len: Option<u64>,
+    // Parts of the file holding code
+    pub code: Vec<Section>,
+    // Parts of the file loaded into memory, used to find the address of a file offset
+    pub segments: Vec<Section>,
+}
+
+/// Range of the file that is loaded at `address`
+#[derive(Clone, Copy)]
+pub struct Section {
+    pub file_offset: [usize; 2],
+    pub address: u64,
+}
+
+impl Container {
+    /// Address the byte at the file offset is loaded at
+    pub fn address(&self, file_offset: usize) -> Option<u64> {
+        self.segments
+            .iter()
+            .chain(self.code.iter())
+            .find(|section| (section.file_offset[0]..section.file_offset[1]).contains(&file_offset))
+            .map(|section| section.address + (file_offset - section.file_offset[0]) as u64)
+    }
+
+    /// Ranges of code that are loaded as in the file, in file order. Code sections that are close to each other and
+    /// loaded with the same difference between address and file offset (i.e .init, .plt, .text and .fini) are joined.
+    pub fn code_ranges(&self) -> Vec<Section> {
+        let mut code = self.code.clone();
+        code.sort_unstable_by_key(|section| section.file_offset[0]);
+
+        let delta = |section: &Section| section.address.wrapping_sub(section.file_offset[0] as u64);
+        let mut ranges: Vec<Section> = Vec::new();
+        for section in code {
+            match ranges.last_mut() {
+                Some(range)
+                    if delta(range) == delta(&section)
+                        && section.file_offset[0] <= range.file_offset[1] + 0x100 =>
+                {
+                    range.file_offset[1] = range.file_offset[1].max(section.file_offset[1]);
+                }
+                _ => ranges.push(section),
+            }
+        }
+        ranges
+    }
+
+    /// The largest of the `code_ranges`
+    pub fn code_range(&self) -> Option<Section> {
+        self.code_ranges()
+            .into_iter()
+            .max_by_key(|range| range.file_offset[1] - range.file_offset[0])
+    }
+}
+
+/// Recognises executable file formats, None for raw binaries and unknown or broken headers
+pub fn parse_container(binary: &[u8]) -> Option<Container> {
+    parse_elf(binary)
+        .or_else(|| parse_pe(binary))
+        .or_else(|| parse_macho(binary))
+}
+
+/// Fills in the endiannes, the range of code and its address from the container, and the instruction length of 32
+/// bit containers when it is to be detected. Parameters given on the command line take precedence. Intel HEX and
+/// S-record files are decoded into the image of their data, whose blocks are analysed together through a memory
+/// map of the record addresses, as are the code ranges of executables with more than one. Raw binaries are left as
+/// they are.
+pub fn apply_container(config: Config, binary: Vec<u8>) -> (Config, Vec<u8>) {
+    let (binary, container) = match parse_hex_records(&binary) {
+        Some((image, container)) if !analyses_a_single_range(&config) => {
+            let Some(memory_map) = sections_memory_map(&container.code) else {
+                return (config, image);
+            };
+            let image = memory_map
+                .image(&image)
+                .expect("blocks are within the image");
+            let config = Config {
+                memory_map: Some(memory_map),
+                ..config
+            };
+            return (config, image);
+        }
+        Some((image, container)) => (image, container),
+        None => match parse_container(&binary) {
+            Some(container) => (binary, container),
+            None => return (config, binary),
+        },
+    };
+
+    let endiannes = match config.endiannes {
+        Endiannes::Unknown => container.endiannes,
+        endiannes => endiannes,
+    };
+    let config = Config {
+        endiannes,
+        ..config
+    };
+    // 64 bit ISAs mostly keep 32 bit instructions, thus only 32 bit words are taken as the instruction length
+    let config = match container.word_len {
+        Some(32) if config.infer_instr_len => config.with_instr_len(32).unwrap_or(config),
+        _ => config,
+    };
+
+    // Code ranges at overlapping addresses can not be mapped, and fall back to the largest as well
+    let code_ranges = container.code_ranges();
+    if code_ranges.len() > 1 && !analyses_a_single_range(&config) {
+        if let Some(memory_map) = sections_memory_map(&code_ranges) {
+            let image = memory_map
+                .image(&binary)
+                .expect("sections are within the file");
+            let config = Config {
+                memory_map: Some(memory_map),
+                ..config
+            };
+            return (config, image);
+        }
+    }
+
+    let file_offset = config
+        .file_offset
+        .or_else(|| container.code_range().map(|range| range.file_offset));
+    let pc_offset = config
+        .pc_offset
+        .or_else(|| file_offset.and_then(|[start, _]| container.address(start)));
+    let config = Config {
+        file_offset,
+        pc_offset,
+        ..config
+    };
+    (config, binary)
+}
+
+// The options that conflict with --memory-map, with which only the largest range of code is analysed
+fn analyses_a_single_range(config: &Config) -> bool {
+    config.file_offset.is_some()
+        || config.pc_offset.is_some()
+        || config.infer_pc_offset
+        || config.detect_code_regions
+        || config.bit_packed
+}
+
+// Every section is taken as code, records do not tell code from data. None if all sections are empty or two overlap.
+fn sections_memory_map(sections: &[Section]) -> Option<MemoryMap> {
+    let regions = sections
+        .iter()
+        .filter(|section| section.file_offset[0] < section.file_offset[1])
+        .map(|section| Region {
+            file_offset: section.file_offset,
+            address: section.address,
+            read: true,
+            write: false,
+            execute: true,
+        })
+        .collect();
+    MemoryMap::new(regions).ok()
+}
+
+/// Unsigned integer of `len` bytes at `offset`, None if out of bounds. Used to read container headers.
+pub fn read_word(binary: &[u8], endiannes: &Endiannes, offset: usize, len: usize) -> Option<u64> {
+    let bytes = binary.get(offset..offset.checked_add(len)?)?;
+    let word = match endiannes {
+        Endiannes::Little => bytes
+            .iter()
+            .rev()
+            .fold(0, |word, &byte| (word << BYTE_SIZE) | byte as u64),
+        _ => bytes
+            .iter()
+            .fold(0, |word, &byte| (word << BYTE_SIZE) | byte as u64),
+    };
+    Some(word)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Writes `len` bytes of `value` at `offset`, the inverse of `read_word`. Used to build container headers.
+    pub(crate) fn write_word(
+        binary: &mut [u8],
+        endiannes: &Endiannes,
+        offset: usize,
+        len: usize,
+        value: u64,
+    ) {
+        let bytes = &mut binary[offset..offset + len];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            let shift = match endiannes {
+                Endiannes::Little => index,
+                _ => len - 1 - index,
+            } as u64
+                * BYTE_SIZE;
+            *byte = (value >> shift) as u8;
+        }
+    }
+    use crate::elf::tests::elf64;
+    use crate::hex_records::tests::intel_hex;
+
+    #[test]
+    fn all_record_blocks_are_analysed() {
+        // A 4 KiB bootloader at 0x0 and an 8 KiB application at 0x4000
+        let text: String = (0..0x3000u32)
+            .step_by(0x10)
+            .map(|offset| {
+                let address = if offset < 0x1000 {
+                    offset
+                } else {
+                    offset + 0x3000
+                };
+                intel_hex(address as u16, 0x00, &[0xab; 0x10])
+            })
+            .collect();
+
+        let (config, image) = apply_container(Config::new(32, 6).unwrap(), text.into_bytes());
+        let memory_map = config.memory_map.unwrap();
+        assert_eq!(config.file_offset, None);
+        assert_eq!(image.len(), 0x3000);
+        assert_eq!(
+            memory_map
+                .code_regions()
+                .map(|region| (region.file_offset, region.address))
+                .collect::<Vec<_>>(),
+            vec![([0, 0x1000], 0x0), ([0x1000, 0x3000], 0x4000)]
+        );
+        assert_eq!(memory_map.image_offset(0x4010), Some(0x1010));
+    }
+
+    #[test]
+    fn given_load_address_analyses_the_largest_block() {
+        let text = [
+            intel_hex(0x0, 0x00, &[1; 4]),
+            intel_hex(0x100, 0x00, &[2; 8]),
+        ]
+        .concat();
+        let config = Config {
+            pc_offset: Some(0x100),
+            ..Config::new(32, 6).unwrap()
+        };
+        let (config, _) = apply_container(config, text.into_bytes());
+        assert!(config.memory_map.is_none());
+        assert_eq!(config.file_offset, Some([4, 12]));
+    }
+
+    #[test]
+    fn all_code_ranges_of_an_executable_are_analysed() {
+        let (config, image) = apply_container(Config::new(32, 6).unwrap(), elf64());
+        let memory_map = config.memory_map.unwrap();
+        let regions: Vec<_> = memory_map
+            .code_regions()
+            .map(|region| (region.file_offset, region.address))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![([0x180, 0x300], 0x400180), ([0x300, 0x380], 0xbfc00000)]
+        );
+        assert_eq!(image.len(), 0x200);
+        assert_eq!(memory_map.address(0x180), Some(0xbfc00000));
+        assert!(matches!(config.endiannes, Endiannes::Little));
+    }
+
+    #[test]
+    fn given_load_address_analyses_the_largest_code_range() {
+        let config = Config {
+            pc_offset: Some(0x400180),
+            ..Config::new(32, 6).unwrap()
+        };
+        let (config, image) = apply_container(config, elf64());
+        assert!(config.memory_map.is_none());
+        assert_eq!(config.file_offset, Some([0x180, 0x300]));
+        assert_eq!(image.len(), 0x400);
+    }
 }
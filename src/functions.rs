@@ -0,0 +1,279 @@
+use itertools::{Either, Itertools};
+
+use crate::address_space::AddressSpace;
+use crate::edges::instruction_index;
+use crate::iter_instructions::{
+    iter_instruction_stream, iter_instructions, iter_packed_instructions,
+};
+use crate::prelude::*;
+
+/// A function recovered by `recover_functions`, at virtual addresses, see `AddressSpace`
+pub struct Function {
+    pub start: u64,
+    // Exclusive, after the last return, or after the last instruction that is not padding for functions without one
+    pub end: u64,
+    // More than one for functions with early returns
+    pub returns: usize,
+    // Distinct functions calling it, none for functions that are never called directly
+    pub callers: usize,
+    // Distinct functions it calls
+    pub callees: usize,
+}
+
+pub struct FunctionTable {
+    // In increasing order
+    pub functions: Vec<Function>,
+    // Addressable units of code, and those within a function
+    pub code_len: usize,
+    pub covered_len: usize,
+}
+
+// An instruction at an offset in the slice, `end` is the offset of the next one
+struct Instruction {
+    offset: usize,
+    end: usize,
+    bits: u64,
+    instr: u64,
+}
+
+impl Function {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl FunctionTable {
+    /// Share of the code within a function
+    pub fn coverage(&self) -> f64 {
+        match self.code_len {
+            0 => 0.0,
+            code_len => self.covered_len as f64 / code_len as f64,
+        }
+    }
+}
+
+impl Instruction {
+    // Zeroes and erased flash fill the space between functions
+    fn is_padding(&self) -> bool {
+        self.instr == 0 || self.instr == u64::MAX >> (u64::BITS as u64 - self.bits)
+    }
+}
+
+/// Functions of a slice from its valid call edges (see `filter_valid_edges`) and its returns. Every call target
+/// starts a function, which ends at its last return before the next call target, thus returns before the last one
+/// are early returns. Code after the last return of a function, and before the first call target, starts functions
+/// that are never called directly, i.e. interrupt handlers or functions only called through pointers. A function
+/// without returns ends at its last instruction that is not padding. Functions that are never called directly and
+/// lie between two returns of a called function can not be told apart from it.
+pub fn recover_functions(
+    binary_slice: &[u8],
+    ret_opcode: u64,
+    config: &Config,
+    endiannes: &Endiannes,
+    address_space: &AddressSpace,
+    call_edges: &[(usize, usize)],
+) -> FunctionTable {
+    let instructions = decode_instructions(binary_slice, config, endiannes);
+    let is_ret =
+        |instruction: &&Instruction| instruction.instr & config.ret_opcode_mask == ret_opcode;
+    let targets: Vec<usize> = call_edges
+        .iter()
+        .map(|&(_, to)| to)
+        .filter(|&to| {
+            instruction_index(&instructions, to, |instruction| instruction.offset).is_some()
+        })
+        .sorted_unstable()
+        .dedup()
+        .collect();
+
+    // Ends before `limit` at the last return, or else the last instruction that is not padding
+    let function_end = |start: usize, limit: usize| -> (usize, usize) {
+        let first = instructions.partition_point(|instruction| instruction.offset < start);
+        let last = instructions.partition_point(|instruction| instruction.end <= limit);
+        let body = &instructions[first..last.max(first)];
+        let returns = body.iter().filter(is_ret).count();
+        let end = match body.iter().rev().find(is_ret) {
+            Some(ret) => ret.end,
+            None => body
+                .iter()
+                .rev()
+                .find(|instruction| !instruction.is_padding())
+                .or(body.first())
+                .map_or(start, |instruction| instruction.end),
+        };
+        (end, returns)
+    };
+    // Functions that are never called directly from `from` to `limit`, there is at most one after a return
+    let uncalled_functions = |mut from: usize, limit: usize, functions: &mut Vec<[usize; 3]>| {
+        while let Some(start) = instructions
+            [instructions.partition_point(|instruction| instruction.offset < from)..]
+            .iter()
+            .take_while(|instruction| instruction.end <= limit)
+            .find(|instruction| !instruction.is_padding())
+            .map(|instruction| instruction.offset)
+        {
+            let (end, returns) = function_end(start, limit);
+            functions.push([start, end, returns]);
+            from = end;
+        }
+    };
+
+    // [start, end, returns] in slice offsets
+    let mut functions: Vec<[usize; 3]> = Vec::new();
+    let code_ranges = address_space.code_ranges();
+    for &[range_start, range_end] in code_ranges.iter() {
+        let starts = &targets[targets.partition_point(|&target| target < range_start)
+            ..targets.partition_point(|&target| target < range_end)];
+        let mut from = range_start;
+        for (index, &start) in starts.iter().enumerate() {
+            uncalled_functions(from, start, &mut functions);
+            let (end, returns) = function_end(start, *starts.get(index + 1).unwrap_or(&range_end));
+            functions.push([start, end, returns]);
+            from = end;
+        }
+        uncalled_functions(from, range_end, &mut functions);
+    }
+
+    // Calls are between the functions containing the call site and starting at the target
+    let function_at =
+        |offset: usize| match functions.partition_point(|&[start, _, _]| start <= offset) {
+            0 => None,
+            index => (offset < functions[index - 1][1]).then_some(index - 1),
+        };
+    let calls: Vec<(usize, usize)> = call_edges
+        .iter()
+        .filter_map(|&(from, to)| Some((function_at(from)?, function_at(to)?)))
+        .sorted_unstable()
+        .dedup()
+        .collect();
+    let mut degrees = vec![[0, 0]; functions.len()];
+    for &(caller, callee) in calls.iter() {
+        degrees[callee][0] += 1;
+        degrees[caller][1] += 1;
+    }
+
+    let address = |offset: usize| address_space.address(offset).unwrap_or_default();
+    FunctionTable {
+        functions: functions
+            .iter()
+            .zip(degrees)
+            .map(|(&[start, end, returns], [callers, callees])| Function {
+                start: address(start),
+                // Empty functions end where they start
+                end: if end > start {
+                    address(end - 1) + 1
+                } else {
+                    address(start)
+                },
+                returns,
+                callers,
+                callees,
+            })
+            .collect(),
+        code_len: code_ranges.iter().map(|&[start, end]| end - start).sum(),
+        covered_len: functions.iter().map(|&[start, end, _]| end - start).sum(),
+    }
+}
+
+// All instructions of the slice in order, at the offsets `find_potential_edges` uses
+fn decode_instructions(
+    binary_slice: &[u8],
+    config: &Config,
+    endiannes: &Endiannes,
+) -> Vec<Instruction> {
+    match &config.length_rule {
+        Some(length_rule) => iter_instruction_stream(binary_slice, endiannes, length_rule)
+            .map(|(offset, instr_len, instr)| Instruction {
+                offset,
+                end: offset + (instr_len / BYTE_SIZE) as usize,
+                bits: instr_len,
+                instr,
+            })
+            .collect(),
+        None => {
+            let instr_addr_len = config.instr_addr_len();
+            let instructions = if config.bit_packed {
+                Either::Left(iter_packed_instructions(
+                    binary_slice,
+                    endiannes,
+                    config.instr_len,
+                ))
+            } else {
+                Either::Right(iter_instructions(binary_slice, endiannes, config.instr_len))
+            };
+            instructions
+                .enumerate()
+                .map(|(index, instr)| Instruction {
+                    offset: index * instr_addr_len,
+                    end: (index + 1) * instr_addr_len,
+                    bits: config.instr_len,
+                    instr,
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RET: u32 = 0x03e00008;
+    const CODE: u32 = 0x24000001;
+
+    fn functions(words: &[u32], call_edges: &[(usize, usize)]) -> Vec<[u64; 3]> {
+        let binary: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let config = Config {
+            pc_offset: Some(0x1000),
+            ..Config::new(32, 6).unwrap()
+        };
+        let instruction_configuration = InstructionConfiguration {
+            byte_offset: 0,
+            endiannes: Endiannes::Little,
+            addressing_mode: AddressingMode::Relative,
+            pc_bias: 0,
+            left_shift_call_operand: 0,
+        };
+        let address_space = AddressSpace::new(&binary, &config, &instruction_configuration);
+        recover_functions(
+            &binary,
+            RET as u64,
+            &config,
+            &Endiannes::Little,
+            &address_space,
+            call_edges,
+        )
+        .functions
+        .iter()
+        .map(|function| [function.start, function.end, function.returns as u64])
+        .collect()
+    }
+
+    #[test]
+    fn unaligned_targets_are_not_functions() {
+        let words = [CODE, CODE, RET, CODE, CODE, RET];
+        // Both targets fall within the instruction at byte 4
+        assert_eq!(
+            functions(&words, &[(0, 5), (0, 7), (0, 12)]),
+            vec![[0x1000, 0x100c, 1], [0x100c, 0x1018, 1]]
+        );
+    }
+
+    #[test]
+    fn function_without_returns_ends_before_padding() {
+        let words = [CODE, RET, CODE, CODE, 0, 0];
+        assert_eq!(
+            functions(&words, &[(0, 8)]),
+            vec![[0x1000, 0x1008, 1], [0x1008, 0x1010, 0]]
+        );
+    }
+
+    #[test]
+    fn early_returns_stay_in_the_function() {
+        let words = [CODE, RET, CODE, RET, CODE, RET];
+        assert_eq!(
+            functions(&words, &[(0, 0), (0, 16)]),
+            vec![[0x1000, 0x1010, 2], [0x1010, 0x1018, 1]]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::file::{Container, Section};
+use crate::prelude::*;
+
+// (address, data) of a data record
+type Record = (u64, Vec<u8>);
+
+/// Decodes Intel HEX or Motorola S-record text into an image of the data blocks without the gaps between them, along
+/// with the address of each block. None if the text is not entirely valid records of either format.
+pub fn parse_hex_records(text: &[u8]) -> Option<(Vec<u8>, Container)> {
+    let text = std::str::from_utf8(text).ok()?;
+    let lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+    let records = match lines.clone().next()?.as_bytes() {
+        [b':', ..] => parse_intel_hex(lines)?,
+        [b'S', b'0'..=b'9', ..] => lines.map(parse_srecord).collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+    let records: Vec<Record> = records.into_iter().flatten().collect();
+    if records.is_empty() {
+        return None;
+    }
+
+    let (image, blocks) = join_records(records);
+    Some((
+        image,
+        Container {
+            endiannes: Endiannes::Unknown,
+            word_len: None,
+            code: blocks.clone(),
+            segments: blocks,
+        },
+    ))
+}
+
+// Data records are Some, other records None
+fn parse_intel_hex<'a>(lines: impl Iterator<Item = &'a str>) -> Option<Vec<Option<Record>>> {
+    // Set by extended segment (type 02) and extended linear (type 04) address records
+    let mut base = 0;
+    let mut records = Vec::new();
+    for line in lines {
+        let bytes = decode_hex(line.strip_prefix(':')?)?;
+        // Length, two bytes of address and type, then data and a checksum making the sum zero
+        let (&len, rest) = bytes.split_first()?;
+        if rest.len() != len as usize + 4
+            || bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0
+        {
+            return None;
+        }
+        let address = u16::from_be_bytes([rest[0], rest[1]]) as u64;
+        let data = &rest[3..rest.len() - 1];
+        let record = match rest[2] {
+            0x00 => Some((base + address, data.to_vec())),
+            0x01 => break,
+            0x02 => {
+                base = (u16::from_be_bytes(data.try_into().ok()?) as u64) << 4;
+                None
+            }
+            0x04 => {
+                base = (u16::from_be_bytes(data.try_into().ok()?) as u64) << 16;
+                None
+            }
+            0x03 | 0x05 => None,
+            _ => return None,
+        };
+        records.push(record);
+    }
+    Some(records)
+}
+
+// Data records are Some(Some), other records Some(None)
+fn parse_srecord(line: &str) -> Option<Option<Record>> {
+    let record_type = line.as_bytes().get(1)?;
+    let bytes = decode_hex(line.get(2..)?)?;
+    // Count of the bytes that follow, address, data and a checksum making the sum 0xff
+    let (&count, rest) = bytes.split_first()?;
+    if rest.len() != count as usize
+        || bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff
+    {
+        return None;
+    }
+    let address_len = match record_type {
+        b'1' => 2,
+        b'2' => 3,
+        b'3' => 4,
+        b'0' | b'5'..=b'9' => return Some(None),
+        _ => return None,
+    };
+    let address = rest
+        .get(..address_len)?
+        .iter()
+        .fold(0, |address, &byte| (address << BYTE_SIZE) | byte as u64);
+    Some(Some((address, rest[address_len..rest.len() - 1].to_vec())))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// Places the records in blocks of contiguous addresses, later records overwrite earlier ones
+fn join_records(mut records: Vec<Record>) -> (Vec<u8>, Vec<Section>) {
+    records.sort_by_key(|&(address, _)| address);
+
+    let mut image: Vec<u8> = Vec::new();
+    let mut blocks: Vec<Section> = Vec::new();
+    for (address, data) in records {
+        match blocks.last_mut() {
+            Some(block)
+                if address
+                    <= block.address + (block.file_offset[1] - block.file_offset[0]) as u64 =>
+            {
+                let start = block.file_offset[0] + (address - block.address) as usize;
+                let end = start + data.len();
+                if end > image.len() {
+                    image.resize(end, 0);
+                }
+                image[start..end].copy_from_slice(&data);
+                block.file_offset[1] = block.file_offset[1].max(end);
+            }
+            _ => {
+                blocks.push(Section {
+                    file_offset: [image.len(), image.len() + data.len()],
+                    address,
+                });
+                image.extend_from_slice(&data);
+            }
+        }
+    }
+    (image, blocks)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Intel HEX record with a valid checksum
+    pub(crate) fn intel_hex(address: u16, record_type: u8, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.push(record_type);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes.push(0u8.wrapping_sub(sum));
+        format!(":{}\n", hex(&bytes))
+    }
+
+    /// S-record of type 1, 2 or 3 with a valid checksum
+    fn srecord(record_type: u8, address: u32, data: &[u8]) -> String {
+        let address_len = record_type as usize + 1;
+        let mut bytes = vec![(address_len + data.len() + 1) as u8];
+        bytes.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes.push(!sum);
+        format!("S{}{}\n", record_type, hex(&bytes))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+
+    fn blocks(container: &Container) -> Vec<([usize; 2], u64)> {
+        container
+            .code
+            .iter()
+            .map(|block| (block.file_offset, block.address))
+            .collect()
+    }
+
+    #[test]
+    fn intel_hex_checksums_are_checked() {
+        let record = intel_hex(0x100, 0x00, &[1, 2, 3, 4]);
+        assert!(parse_hex_records(record.as_bytes()).is_some());
+
+        let corrupt = record.replace("01020304", "01020305");
+        assert!(parse_hex_records(corrupt.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn intel_hex_extended_addresses() {
+        let text = [
+            intel_hex(0x10, 0x00, &[1, 2]),
+            // Extended segment address, 0x1000 << 4
+            intel_hex(0, 0x02, &[0x10, 0x00]),
+            intel_hex(0x10, 0x00, &[3, 4]),
+            // Extended linear address, 0x0800 << 16
+            intel_hex(0, 0x04, &[0x08, 0x00]),
+            intel_hex(0x10, 0x00, &[5, 6]),
+            intel_hex(0, 0x01, &[]),
+        ]
+        .concat();
+        let (image, container) = parse_hex_records(text.as_bytes()).unwrap();
+        assert_eq!(image, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            blocks(&container),
+            vec![([0, 2], 0x10), ([2, 4], 0x10010), ([4, 6], 0x08000010)]
+        );
+    }
+
+    #[test]
+    fn srecord_address_lengths() {
+        let text = [
+            "S00600004844521B\n".to_string(),
+            srecord(1, 0x1234, &[1, 2]),
+            srecord(2, 0x123456, &[3, 4]),
+            srecord(3, 0x12345678, &[5, 6]),
+        ]
+        .concat();
+        let (image, container) = parse_hex_records(text.as_bytes()).unwrap();
+        assert_eq!(image, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            blocks(&container),
+            vec![([0, 2], 0x1234), ([2, 4], 0x123456), ([4, 6], 0x12345678)]
+        );
+
+        let corrupt = srecord(1, 0x1234, &[1, 2]).replace("0102", "0103");
+        assert!(parse_hex_records(corrupt.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn overlapping_records_are_joined() {
+        let text = [
+            intel_hex(0x0, 0x00, &[1, 2, 3, 4]),
+            // Overwrites the last two bytes and extends the block
+            intel_hex(0x2, 0x00, &[5, 6, 7]),
+            // Adjacent, thus the same block
+            intel_hex(0x5, 0x00, &[8]),
+            intel_hex(0x10, 0x00, &[9]),
+        ]
+        .concat();
+        let (image, container) = parse_hex_records(text.as_bytes()).unwrap();
+        assert_eq!(image, vec![1, 2, 5, 6, 7, 8, 9]);
+        assert_eq!(blocks(&container), vec![([0, 6], 0x0), ([6, 7], 0x10)]);
+    }
+}
@@ -0,0 +1,76 @@
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+// Number of most common instructions that make up the opcode histogram sharpness
+const TOP_INSTRUCTIONS: usize = 16;
+
+/// Estimates the instruction length of an unknown fixed width ISA from the raw bytes. Every whole byte width from 16
+/// to 64 bits is scored by
+/// - periodicity, how much the position of a byte within an instruction tells about its value (opcode bytes repeat
+///   far more than operand bytes), in bits
+/// - sharpness, the share of instructions covered by the most common instructions for the best alignment
+///
+/// Multiples of the real width are as periodic but less sharp, divisors are sharp but less periodic.
+/// Returns (instr_len, score) sorted by descending score.
+pub fn instr_len_hypotheses(binary: &[u8]) -> Vec<(u64, f64)> {
+    let entropy_all = entropy(&byte_histogram(binary.iter()));
+
+    let mut hypotheses: Vec<(u64, f64)> = (2..=8)
+        .filter(|&instr_byte_len| binary.len() >= instr_byte_len * TOP_INSTRUCTIONS)
+        .map(|instr_byte_len| {
+            let entropy_positions = (0..instr_byte_len)
+                .map(|position| {
+                    entropy(&byte_histogram(
+                        binary[position..].iter().step_by(instr_byte_len),
+                    ))
+                })
+                .sum::<f64>()
+                / instr_byte_len as f64;
+            let periodicity = entropy_all - entropy_positions;
+
+            let sharpness = (0..instr_byte_len)
+                .map(|alignment| sharpness(&binary[alignment..], instr_byte_len))
+                .fold(0.0, f64::max);
+
+            (instr_byte_len as u64 * BYTE_SIZE, periodicity * sharpness)
+        })
+        .collect();
+
+    hypotheses.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    hypotheses
+}
+
+pub fn byte_histogram<'a>(bytes: impl Iterator<Item = &'a u8>) -> [usize; 256] {
+    let mut histogram = [0; 256];
+    for &byte in bytes {
+        histogram[byte as usize] += 1;
+    }
+    histogram
+}
+
+/// Shannon entropy in bits
+pub fn entropy(histogram: &[usize; 256]) -> f64 {
+    let total: usize = histogram.iter().sum();
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Share of the instructions covered by the TOP_INSTRUCTIONS most common ones
+fn sharpness(binary: &[u8], instr_byte_len: usize) -> f64 {
+    let mut histogram: FxHashMap<&[u8], usize> = Default::default();
+    for instr in binary.chunks_exact(instr_byte_len) {
+        *histogram.entry(instr).or_insert(0) += 1;
+    }
+
+    let mut counts: Vec<usize> = histogram.into_values().collect();
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let total = binary.len() / instr_byte_len;
+    counts.iter().take(TOP_INSTRUCTIONS).sum::<usize>() as f64 / total as f64
+}
@@ -1,36 +1,111 @@
 use crate::prelude::*;
 use itertools::iproduct;
 
+/// One hypothesis of how instructions are stored and call targets are computed, the parts of the configuration that
+/// are tried one by one when unknown
+#[derive(Clone, Copy)]
+pub struct InstructionConfiguration {
+    // Offset of the first instruction from the start of the analysed range
+    pub byte_offset: usize,
+    pub endiannes: Endiannes,
+    pub addressing_mode: AddressingMode,
+    pub pc_bias: i64,
+    pub left_shift_call_operand: u64,
+}
+
+/// The analysed range of the binary from `byte_offset` bytes into it, the slice of a hypothesis. Empty when the
+/// range is shorter than the offset.
+pub fn instruction_slice<'a>(binary: &'a [u8], config: &Config, byte_offset: usize) -> &'a [u8] {
+    let [start, end] = config.file_offset.unwrap_or([0, binary.len()]);
+    binary.get(start + byte_offset..end).unwrap_or_default()
+}
+
 pub fn iter_potential_instruction_configuration<'a>(
     binary: &'a [u8],
     config: &'a Config,
-) -> impl Iterator<Item = (&'a [u8], &'a Endiannes, &'a AddressingMode)> {
-    let instr_byte_len = (config.instr_len / BYTE_SIZE) as usize;
+) -> impl Iterator<Item = (&'a [u8], InstructionConfiguration)> {
+    // For variable length instructions, an instruction can start at any parcel. Bit packed instructions can start
+    // at instr_len / gcd(instr_len, 8) different bit positions relative to a byte boundary, one per starting byte.
+    let instr_byte_len = match &config.length_rule {
+        Some(length_rule) => (length_rule.parcel_len / BYTE_SIZE) as usize,
+        None if config.bit_packed => {
+            (config.instr_len >> config.instr_len.trailing_zeros().min(3)) as usize
+        }
+        None => config.instr_byte_len(),
+    };
 
     let endiannes = if let Endiannes::Unknown = config.endiannes {
-        vec![&Endiannes::Big, &Endiannes::Little]
+        vec![Endiannes::Big, Endiannes::Little]
     } else {
-        vec![&config.endiannes]
+        vec![config.endiannes]
     };
 
     let addressing_mode = if let AddressingMode::Unknown = config.addressing_mode {
-        vec![&AddressingMode::Relative, &AddressingMode::Absolute]
+        // Word addressing is the same as byte addressing when words are a single address unit
+        if config.word_addr_len() > 1 {
+            vec![
+                AddressingMode::Relative,
+                AddressingMode::Absolute,
+                AddressingMode::RelativeWord,
+                AddressingMode::AbsoluteWord,
+            ]
+        } else {
+            vec![AddressingMode::Relative, AddressingMode::Absolute]
+        }
     } else {
-        vec![&config.addressing_mode]
+        vec![config.addressing_mode]
     };
 
-    // TODO also add addressing mode
+    // No bias, the next instruction, or the one after that (i.e classic ARM's PC+8)
+    let pc_bias = if let Some(pc_bias) = config.pc_bias {
+        vec![pc_bias]
+    } else {
+        let pc_inc = config.pc_inc as i64;
+        vec![0, pc_inc, 2 * pc_inc]
+    };
 
-    iproduct!((0..instr_byte_len), endiannes, addressing_mode).filter_map(
-        move |(byte_index, endiannes, addressing_mode)| match byte_index.cmp(&(instr_byte_len)) {
-            std::cmp::Ordering::Less => Some((
-                &binary[config.file_offset[0] + byte_index..config.file_offset[1]],
-                endiannes,
-                addressing_mode,
-            )),
-            _ => unreachable!(),
-        },
-    )
+    // Small shifts (i.e MIPS and AArch64 shift by 2) and the byte length of the instruction, as long as the shifted
+    // operand fits in 64 bits
+    let left_shift_call_operand =
+        if let Some(left_shift_call_operand) = config.left_shift_call_operand {
+            vec![left_shift_call_operand]
+        } else {
+            let mut shifts = vec![0, 1, 2, 3];
+            if !shifts.contains(&(config.instr_byte_len() as u64)) {
+                shifts.push(config.instr_byte_len() as u64);
+            }
+            shifts.retain(|&shift| shift < u64::BITS as u64 - config.call_operand_layout.len());
+            shifts
+        };
+
+    iproduct!((0..instr_byte_len), endiannes, addressing_mode)
+        .filter_map(move |(byte_index, endiannes, addressing_mode)| {
+            match byte_index.cmp(&(instr_byte_len)) {
+                std::cmp::Ordering::Less => Some((byte_index, endiannes, addressing_mode)),
+                _ => unreachable!(),
+            }
+        })
+        .flat_map(move |(byte_index, endiannes, addressing_mode)| {
+            // Absolute targets do not depend on the address of the call, no need to try several biases
+            let pc_bias = match addressing_mode {
+                AddressingMode::Absolute | AddressingMode::AbsoluteWord => &pc_bias[..1],
+                _ => &pc_bias[..],
+            };
+            iproduct!(pc_bias.iter(), left_shift_call_operand.iter())
+                .map(|(&pc_bias, &left_shift_call_operand)| {
+                    (
+                        instruction_slice(binary, config, byte_index),
+                        InstructionConfiguration {
+                            byte_offset: byte_index,
+                            endiannes,
+                            addressing_mode,
+                            pc_bias,
+                            left_shift_call_operand,
+                        },
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
 }
 
 pub fn iter_instructions<'a>(
@@ -44,20 +119,135 @@ pub fn iter_instructions<'a>(
             16 => from_be_bytes_16,
             32 => from_be_bytes_32,
             64 => from_be_bytes_64,
-            _ => unreachable!("Instr_len should only be one of [8, 16, 32, 64]"),
+            _ => from_be_bytes_n,
         },
         Endiannes::Little => match instr_len {
             8 => from_le_bytes_8,
             16 => from_le_bytes_16,
             32 => from_le_bytes_32,
             64 => from_le_bytes_64,
-            _ => unreachable!("Instr_len should only be one of [8, 16, 32, 64]"),
+            _ => from_le_bytes_n,
         },
         Endiannes::Unknown => unimplemented!(),
     };
+    // Instructions that are not a multiple of 8 bits (i.e PIC 12/14 bit) are stored in the lowest bits of the
+    // smallest container of whole bytes that fits them
+    let mask = u64::MAX >> (u64::BITS as u64 - instr_len);
     binary
-        .chunks_exact((instr_len / BYTE_SIZE) as usize)
-        .map(extraction_function)
+        .chunks_exact(instr_len.div_ceil(BYTE_SIZE) as usize)
+        .map(move |data| extraction_function(data) & mask)
+}
+
+/// Instructions packed without padding, i.e two 12 bit instructions in 3 bytes. Big endian reads the bit stream
+/// most significant bit first, little endian least significant bit first.
+pub fn iter_packed_instructions<'a>(
+    binary: &'a [u8],
+    endiannes: &'a Endiannes,
+    instr_len: u64,
+) -> impl Iterator<Item = u64> + 'a {
+    let nr_instructions = binary.len() * BYTE_SIZE as usize / instr_len as usize;
+    (0..nr_instructions)
+        .map(move |index| read_packed_instruction(binary, endiannes, instr_len, index))
+}
+
+/// The instruction at the given instruction index of a bit packed binary
+#[inline]
+pub fn read_packed_instruction(
+    binary: &[u8],
+    endiannes: &Endiannes,
+    instr_len: u64,
+    index: usize,
+) -> u64 {
+    let first_bit = index as u64 * instr_len;
+    let bit_offset = first_bit % BYTE_SIZE;
+    let nr_bytes = (bit_offset + instr_len).div_ceil(BYTE_SIZE);
+    let data = &binary[(first_bit / BYTE_SIZE) as usize..][..nr_bytes as usize];
+    let mask = u64::MAX >> (u64::BITS as u64 - instr_len);
+
+    // At most 9 bytes, which does not fit in a u64
+    match endiannes {
+        Endiannes::Big => {
+            let bits = data
+                .iter()
+                .fold(0u128, |bits, &byte| (bits << 8) | byte as u128);
+            (bits >> (nr_bytes * BYTE_SIZE - bit_offset - instr_len)) as u64 & mask
+        }
+        Endiannes::Little => {
+            let bits = data
+                .iter()
+                .rev()
+                .fold(0u128, |bits, &byte| (bits << 8) | byte as u128);
+            (bits >> bit_offset) as u64 & mask
+        }
+        Endiannes::Unknown => unimplemented!(),
+    }
+}
+
+/// Walks variable length instructions, yielding (byte offset, instruction length, instruction). Stops at the first
+/// instruction that does not fit in the binary.
+pub fn iter_instruction_stream<'a>(
+    binary: &'a [u8],
+    endiannes: &Endiannes,
+    length_rule: &'a LengthRule,
+) -> impl Iterator<Item = (usize, u64, u64)> + 'a {
+    let extract_parcel = match endiannes {
+        Endiannes::Big => match length_rule.parcel_len {
+            8 => from_be_bytes_8,
+            16 => from_be_bytes_16,
+            32 => from_be_bytes_32,
+            _ => unreachable!("Parcel length should only be one of [8, 16, 32]"),
+        },
+        Endiannes::Little => match length_rule.parcel_len {
+            8 => from_le_bytes_8,
+            16 => from_le_bytes_16,
+            32 => from_le_bytes_32,
+            _ => unreachable!("Parcel length should only be one of [8, 16, 32]"),
+        },
+        Endiannes::Unknown => unimplemented!(),
+    };
+    // Reading the instruction as a single word puts the first parcel in the most significant bits for big endian
+    let first_parcel_high = matches!(endiannes, Endiannes::Big) != length_rule.swap_parcels;
+    let parcel_len = length_rule.parcel_len;
+    let parcel_byte_len = (parcel_len / BYTE_SIZE) as usize;
+
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let first_parcel = extract_parcel(binary.get(offset..offset + parcel_byte_len)?);
+        let instr_len = length_rule.instr_len(first_parcel);
+        let instr_byte_len = (instr_len / BYTE_SIZE) as usize;
+        // An empty instruction would never advance the stream
+        if instr_byte_len == 0 {
+            return None;
+        }
+        let parcels = binary.get(offset..offset + instr_byte_len)?;
+
+        let instr = parcels
+            .chunks_exact(parcel_byte_len)
+            .map(extract_parcel)
+            .enumerate()
+            .fold(0, |instr, (i, parcel)| {
+                if first_parcel_high {
+                    (instr << parcel_len) | parcel
+                } else {
+                    instr | (parcel << (i as u64 * parcel_len))
+                }
+            });
+
+        let item = (offset, instr_len, instr);
+        offset += instr_byte_len;
+        Some(item)
+    })
+}
+
+fn from_be_bytes_n(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0, |instr, &byte| (instr << 8) | byte as u64)
+}
+
+fn from_le_bytes_n(data: &[u8]) -> u64 {
+    data.iter()
+        .rev()
+        .fold(0, |instr, &byte| (instr << 8) | byte as u64)
 }
 
 fn from_be_bytes_64(data: &[u8]) -> u64 {
@@ -120,7 +310,7 @@ pub fn iter_instructions_and_search(
                 .chunks_exact((instr_len / BYTE_SIZE) as usize)
                 .map(|data| u64::from_be_bytes(data.try_into().unwrap()))
                 .any(|x| x & mask == target),
-            _ => unreachable!("Instr_len should only be one of [8, 16, 32, 64]"),
+            _ => iter_instructions(binary, endiannes, instr_len).any(|x| x & mask == target),
         },
         Endiannes::Little => match instr_len {
             8 => binary
@@ -139,7 +329,7 @@ pub fn iter_instructions_and_search(
                 .chunks_exact((instr_len / BYTE_SIZE) as usize)
                 .map(|data| u64::from_le_bytes(data.try_into().unwrap()))
                 .any(|x| x & mask == target),
-            _ => unreachable!("Instr_len should only be one of [8, 16, 32, 64]"),
+            _ => iter_instructions(binary, endiannes, instr_len).any(|x| x & mask == target),
         },
         Endiannes::Unknown => unimplemented!(),
     }
@@ -166,3 +356,110 @@ pub fn iter_instructions_and_search(
 // u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as u64
 
 // u32::from_le_bytes(*arrayref::array_ref!(data, 0, 4)) as u64
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyse_binary::Analysis;
+    use itertools::Itertools;
+
+    #[test]
+    fn stream_stops_at_empty_instructions() {
+        // Not accepted by `LengthRule::new`, but must not hang when built directly
+        let length_rule = LengthRule {
+            parcel_len: 16,
+            patterns: vec![LengthPattern {
+                mask: 0x3,
+                value: 0x3,
+                instr_len: 0,
+            }],
+            swap_parcels: false,
+        };
+        let binary = [0x01, 0x00, 0x03, 0x00, 0x01, 0x00];
+        let instructions: Vec<_> =
+            iter_instruction_stream(&binary, &Endiannes::Little, &length_rule).collect();
+        assert_eq!(instructions, vec![(0, 16, 0x1)]);
+    }
+
+    #[test]
+    fn shifts_fit_64_bit_operands() {
+        let config = Config {
+            left_shift_call_operand: None,
+            ..Config::new(64, 6).unwrap()
+        };
+        let binary: Vec<u8> = (0..64u64)
+            .flat_map(|index| (index.wrapping_mul(0x9e3779b97f4a7c15)).to_be_bytes())
+            .collect();
+        let shifts: Vec<u64> = iter_potential_instruction_configuration(&binary, &config)
+            .map(|(_, instruction_configuration)| instruction_configuration.left_shift_call_operand)
+            .sorted_unstable()
+            .dedup()
+            .collect();
+        assert_eq!(shifts, vec![0, 1, 2, 3]);
+
+        // Operands of random words overflow when shifted and scaled, which must not panic
+        Analysis::from_config(config).run(&binary);
+    }
+
+    // Packs the values into a bit stream, most significant bit first for big endian and least significant bit first
+    // for little endian
+    fn pack(values: &[u64], endiannes: &Endiannes, instr_len: u64) -> Vec<u8> {
+        let nr_bits = values.len() * instr_len as usize;
+        let mut binary = vec![0u8; nr_bits.div_ceil(BYTE_SIZE as usize)];
+        for (index, &value) in values.iter().enumerate() {
+            for bit in 0..instr_len as usize {
+                let position = index * instr_len as usize + bit;
+                let (value_bit, byte_bit) = match endiannes {
+                    Endiannes::Big => (instr_len as usize - 1 - bit, 7 - position % 8),
+                    _ => (bit, position % 8),
+                };
+                binary[position / 8] |= ((value >> value_bit & 1) as u8) << byte_bit;
+            }
+        }
+        binary
+    }
+
+    #[test]
+    fn packed_instructions_round_trip() {
+        for instr_len in [12, 18] {
+            let mask = (1 << instr_len) - 1;
+            let values: Vec<u64> = (1..=9u64)
+                .map(|index| index.wrapping_mul(0x9e3779b97f4a7c15) >> 20 & mask)
+                .chain([0, mask])
+                .collect();
+            for endiannes in [Endiannes::Big, Endiannes::Little] {
+                let binary = pack(&values, &endiannes, instr_len);
+                let read: Vec<u64> =
+                    iter_packed_instructions(&binary, &endiannes, instr_len).collect();
+                assert_eq!(read, values);
+                for (index, &value) in values.iter().enumerate() {
+                    assert_eq!(
+                        read_packed_instruction(&binary, &endiannes, instr_len, index),
+                        value
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packed_bit_order_follows_the_endiannes() {
+        // 0xabc and 0xdef
+        assert_eq!(
+            iter_packed_instructions(&[0xab, 0xcd, 0xef], &Endiannes::Big, 12).collect::<Vec<_>>(),
+            vec![0xabc, 0xdef]
+        );
+        assert_eq!(
+            iter_packed_instructions(&[0xbc, 0xfa, 0xde], &Endiannes::Little, 12)
+                .collect::<Vec<_>>(),
+            vec![0xabc, 0xdef]
+        );
+    }
+
+    #[test]
+    fn binaries_shorter_than_an_instruction_have_no_candidates() {
+        let analysis = Analysis::new(32, 6).unwrap();
+        assert!(analysis.run(&[0x0c, 0x10]).is_empty());
+        assert!(analysis.run(&[]).is_empty());
+    }
+}
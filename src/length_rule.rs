@@ -0,0 +1,203 @@
+use std::fmt;
+use std::str::FromStr;
+
+use clap_num::maybe_hex;
+use itertools::Itertools;
+use rustc_hash::FxHashSet;
+
+/// Decides the length of an instruction from its first parcel (the smallest instruction unit), used for ISAs with
+/// mixed instruction widths such as RISC-V with the C extension or Thumb-2.
+/// The first matching pattern decides the length, if no pattern matches the instruction is a single parcel.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LengthRule {
+    pub parcel_len: u64,
+    pub patterns: Vec<LengthPattern>,
+    // By default an instruction is read as a single word with the given endiannes, i.e the first parcel is the
+    // least significant for little endian. Thumb-2 stores the most significant halfword first even though it is
+    // little endian, which requires swapping.
+    pub swap_parcels: bool,
+}
+
+/// `first_parcel & mask == value` means the instruction is `instr_len` bits long
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LengthPattern {
+    pub mask: u64,
+    pub value: u64,
+    pub instr_len: u64,
+}
+
+impl LengthRule {
+    pub fn new(
+        parcel_len: u64,
+        patterns: Vec<LengthPattern>,
+        swap_parcels: bool,
+    ) -> Result<LengthRule, String> {
+        if ![8, 16, 32].contains(&parcel_len) {
+            return Err(format!(
+                "parcel length must be one of [8, 16, 32], got {}",
+                parcel_len
+            ));
+        }
+        for pattern in patterns.iter() {
+            if pattern.instr_len % parcel_len != 0
+                || pattern.instr_len < parcel_len
+                || pattern.instr_len > u64::BITS as u64
+            {
+                return Err(format!(
+                    "instruction length {} must be a positive multiple of the parcel length {} and at most 64",
+                    pattern.instr_len, parcel_len
+                ));
+            }
+        }
+
+        Ok(LengthRule {
+            parcel_len,
+            patterns,
+            swap_parcels,
+        })
+    }
+
+    /// RISC-V with the C extension, the two lowest bits being 0b11 means a 32 bit instruction
+    pub fn riscv_c() -> LengthRule {
+        LengthRule {
+            parcel_len: 16,
+            patterns: vec![LengthPattern {
+                mask: 0b11,
+                value: 0b11,
+                instr_len: 32,
+            }],
+            swap_parcels: false,
+        }
+    }
+
+    /// Thumb-2, a first halfword starting with 0b11101, 0b11110 or 0b11111 means a 32 bit instruction
+    pub fn thumb2() -> LengthRule {
+        LengthRule {
+            parcel_len: 16,
+            patterns: [0xe800, 0xf000, 0xf800]
+                .into_iter()
+                .map(|value| LengthPattern {
+                    mask: 0xf800,
+                    value,
+                    instr_len: 32,
+                })
+                .collect(),
+            swap_parcels: true,
+        }
+    }
+
+    /// Candidate rules for an unknown ISA where instructions are either one parcel or `instr_len` bits. The length is
+    /// assumed to be decided by the lowest or highest 1 to 5 bits of the first parcel being at least some value,
+    /// which covers both RISC-V (lowest two bits 0b11) and Thumb-2 (highest five bits at least 0b11101).
+    /// Includes the rule where all instructions are `instr_len` bits as a baseline.
+    pub fn hypotheses(parcel_len: u64, instr_len: u64, swap_parcels: bool) -> Vec<LengthRule> {
+        let mut rules = vec![LengthRule {
+            parcel_len,
+            patterns: vec![LengthPattern {
+                mask: 0,
+                value: 0,
+                instr_len,
+            }],
+            swap_parcels,
+        }];
+
+        for field_len in 1..=5 {
+            for field_shift in [0, parcel_len - field_len] {
+                let mask = ((1 << field_len) - 1) << field_shift;
+                for threshold in 1..(1 << field_len) {
+                    rules.push(LengthRule {
+                        parcel_len,
+                        patterns: (threshold..(1 << field_len))
+                            .map(|value| LengthPattern {
+                                mask,
+                                value: value << field_shift,
+                                instr_len,
+                            })
+                            .collect(),
+                        swap_parcels,
+                    });
+                }
+            }
+        }
+
+        // Many of the rules are equivalent, i.e highest two bits at least 0b10 is the same as highest bit set.
+        // Small parcels can be compared by which parcel values they consider long.
+        if parcel_len <= 16 {
+            let mut seen: FxHashSet<Vec<bool>> = Default::default();
+            rules.retain(|rule| {
+                seen.insert(
+                    (0..1 << parcel_len)
+                        .map(|parcel| rule.instr_len(parcel) != parcel_len)
+                        .collect(),
+                )
+            });
+        }
+        rules
+    }
+
+    #[inline]
+    pub fn instr_len(&self, first_parcel: u64) -> u64 {
+        self.patterns
+            .iter()
+            .find(|pattern| first_parcel & pattern.mask == pattern.value)
+            .map_or(self.parcel_len, |pattern| pattern.instr_len)
+    }
+}
+
+impl fmt::Display for LengthRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.patterns.iter().join(" "))
+    }
+}
+
+impl FromStr for LengthPattern {
+    type Err = String;
+
+    /// Parses `MASK=VALUE:LEN`, i.e `0x3=0x3:32`
+    fn from_str(value: &str) -> Result<LengthPattern, String> {
+        let error = || {
+            format!(
+                "invalid length pattern '{}', expected MASK=VALUE:LEN",
+                value
+            )
+        };
+        let (mask, rest) = value.split_once('=').ok_or_else(error)?;
+        let (pattern_value, instr_len) = rest.split_once(':').ok_or_else(error)?;
+
+        Ok(LengthPattern {
+            mask: maybe_hex::<u64>(mask.trim()).map_err(|_| error())?,
+            value: maybe_hex::<u64>(pattern_value.trim()).map_err(|_| error())?,
+            instr_len: instr_len.trim().parse().map_err(|_| error())?,
+        })
+    }
+}
+
+impl fmt::Display for LengthPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}={:#x}:{}", self.mask, self.value, self.instr_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(instr_len: u64) -> Result<LengthRule, String> {
+        let pattern = LengthPattern {
+            mask: 0x3,
+            value: 0x3,
+            instr_len,
+        };
+        LengthRule::new(16, vec![pattern], false)
+    }
+
+    #[test]
+    fn instruction_lengths_are_whole_parcels() {
+        assert!(rule(32).is_ok());
+        assert!(rule(16).is_ok());
+        assert!(rule(0).is_err());
+        assert!(rule(8).is_err());
+        assert!(rule(24).is_err());
+        assert!(rule(80).is_err());
+    }
+}
@@ -0,0 +1,30 @@
+pub mod address_space;
+pub mod analyse_binary;
+pub mod base_address;
+pub mod call_graph;
+pub mod candidates_opcodes;
+pub mod cli;
+pub mod code_regions;
+pub mod edges;
+pub mod elf;
+pub mod file;
+pub mod functions;
+pub mod hex_records;
+pub mod instr_len;
+pub mod iter_instructions;
+pub mod length_rule;
+pub mod macho;
+pub mod memory_map;
+pub mod min_heap;
+pub mod operand_layout;
+pub mod pe;
+pub mod prelude;
+pub mod report;
+pub mod scorer;
+
+pub use analyse_binary::Analysis;
+pub use candidates_opcodes::{call_candidates, ret_candidates};
+pub use edges::{filter_valid_edges, find_potential_edges};
+pub use min_heap::Candidate;
+pub use prelude::*;
+pub use scorer::{EdgeScorer, TargetScorer};
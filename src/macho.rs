@@ -0,0 +1,150 @@
+use crate::file::{read_word, Container, Section};
+use crate::prelude::*;
+
+const LC_SEGMENT: u64 = 0x1;
+const LC_SEGMENT_64: u64 = 0x19;
+const VM_PROT_EXECUTE: u64 = 0x4;
+const S_ATTR_PURE_INSTRUCTIONS: u64 = 0x80000000;
+const S_ATTR_SOME_INSTRUCTIONS: u64 = 0x400;
+
+/// Reads the Mach-O header, whatever the cpu type. Code is taken from the sections holding instructions, or the
+/// executable segments if there are no such sections. Fat binaries are not supported. None if the binary is not a
+/// Mach-O or the headers are truncated.
+pub fn parse_macho(binary: &[u8]) -> Option<Container> {
+    let (endiannes, word_len) = match read_word(binary, &Endiannes::Big, 0, 4)? {
+        0xfeedface => (Endiannes::Big, 32),
+        0xcefaedfe => (Endiannes::Little, 32),
+        0xfeedfacf => (Endiannes::Big, 64),
+        0xcffaedfe => (Endiannes::Little, 64),
+        _ => return None,
+    };
+    let read = |offset: usize, len: usize| read_word(binary, &endiannes, offset, len);
+    // Addresses and sizes are words, offsets within the file are 32 bits in sections
+    let word = (word_len / BYTE_SIZE) as usize;
+
+    let number_of_commands = read(16, 4)?;
+    let mut command = if word_len == 32 { 28 } else { 32 };
+
+    let mut code_sections = Vec::new();
+    let mut code_segments = Vec::new();
+    let mut segments = Vec::new();
+    for _ in 0..number_of_commands {
+        if command > binary.len() {
+            return None;
+        }
+        let cmd = read(command, 4)?;
+        let cmdsize = read(command + 4, 4)? as usize;
+        if cmd == LC_SEGMENT || cmd == LC_SEGMENT_64 {
+            // segname takes 16 bytes, then vmaddr, vmsize, fileoff and filesize are words
+            let vmaddr = read(command + 24, word)?;
+            let fileoff = read(command + 24 + 2 * word, word)?;
+            let filesize = read(command + 24 + 3 * word, word)?;
+            let fields = command + 24 + 4 * word;
+            let initprot = read(fields + 4, 4)?;
+            let nsects = read(fields + 8, 4)? as usize;
+
+            if filesize > 0 {
+                let segment = Section {
+                    file_offset: [fileoff as usize, fileoff.saturating_add(filesize) as usize],
+                    address: vmaddr,
+                };
+                if initprot & VM_PROT_EXECUTE != 0 {
+                    code_segments.push(segment);
+                }
+                segments.push(segment);
+            }
+
+            // sectname and segname take 16 bytes each, then addr and size are words
+            let section_len = if word_len == 32 { 68 } else { 80 };
+            for index in 0..nsects {
+                let section = fields + 16 + index * section_len;
+                let addr = read(section + 32, word)?;
+                let size = read(section + 32 + word, word)?;
+                let offset = read(section + 32 + 2 * word, 4)?;
+                let flags = read(section + 32 + 2 * word + 16, 4)?;
+                if flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) != 0 && size > 0 {
+                    code_sections.push(Section {
+                        file_offset: [offset as usize, offset.saturating_add(size) as usize],
+                        address: addr,
+                    });
+                }
+            }
+        }
+        if cmdsize == 0 {
+            return None;
+        }
+        command += cmdsize;
+    }
+
+    let code = if code_sections.is_empty() {
+        code_segments
+    } else {
+        code_sections
+    };
+    if code
+        .iter()
+        .chain(segments.iter())
+        .any(|section| section.file_offset[1] > binary.len())
+    {
+        return None;
+    }
+
+    Some(Container {
+        endiannes,
+        word_len: Some(word_len),
+        code,
+        segments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::tests::write_word;
+
+    // 32 bit little endian Mach-O with a segment of the whole file at 0x1000 and a __text section of 0x20 bytes at
+    // file offset 0x100
+    fn macho32() -> Vec<u8> {
+        let mut binary = vec![0; 0x120];
+        let mut write = |offset: usize, len: usize, value: u64| {
+            write_word(&mut binary, &Endiannes::Little, offset, len, value)
+        };
+        write(0, 4, 0xfeedface);
+        write(16, 4, 1);
+        // LC_SEGMENT with one section, readable and executable
+        let command = 28;
+        write(command, 4, LC_SEGMENT);
+        write(command + 4, 4, 56 + 68);
+        write(command + 24, 4, 0x1000);
+        write(command + 28, 4, 0x120);
+        write(command + 32, 4, 0);
+        write(command + 36, 4, 0x120);
+        write(command + 44, 4, VM_PROT_EXECUTE | 0x1);
+        write(command + 48, 4, 1);
+        let section = command + 56;
+        write(section + 32, 4, 0x1100);
+        write(section + 36, 4, 0x20);
+        write(section + 40, 4, 0x100);
+        write(
+            section + 56,
+            4,
+            S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS,
+        );
+        binary
+    }
+
+    #[test]
+    fn text_section_is_code() {
+        let container = parse_macho(&macho32()).unwrap();
+        assert!(matches!(container.endiannes, Endiannes::Little));
+        assert_eq!(container.word_len, Some(32));
+        let code = container.code_range().unwrap();
+        assert_eq!((code.file_offset, code.address), ([0x100, 0x120], 0x1100));
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        assert!(parse_macho(&macho32()[..0x50]).is_none());
+        assert!(parse_macho(&macho32()[..0x10]).is_none());
+    }
+}
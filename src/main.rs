@@ -1,115 +1,335 @@
-mod candidates_opcodes;
-mod cli;
-mod edges;
-mod file;
-mod iter_instructions;
-mod min_heap;
-mod prelude;
-
-use rayon::prelude::*;
-
-use candidates_opcodes::{call_candidates, ret_candidates};
-use edges::{filter_valid_edges, find_potential_edges};
-use iter_instructions::iter_potential_instruction_configuration;
-use min_heap::{Candidate, MinHeap};
-use prelude::*;
+use binary_analysis_rs::base_address::find_base_address;
+use binary_analysis_rs::cli::cli_clap::{parse_parameters, BasefindParameters, Command};
+use binary_analysis_rs::code_regions::{code_region_map, detect_code_regions};
+use binary_analysis_rs::functions::FunctionTable;
+use binary_analysis_rs::report::{CallSiteReport, CandidateReport, CodeRegionReport, Mode, Report};
+use binary_analysis_rs::{file, Analysis, Candidate, Config, GraphFormat, OutputFormat};
+use clap::ValueEnum;
+
+// Calls of each candidate in JSON output without --include-instructions
+const SAMPLE_CALL_SITES: usize = 10;
 
 fn main() {
-    let config = Config::get();
+    let parameters = parse_parameters();
+    // The functions subcommand analyses the binary as usual before recovering the functions
+    let (parameters, recover_functions) = match parameters.command {
+        Some(Command::Basefind(parameters)) => {
+            basefind(&parameters);
+            return;
+        }
+        Some(Command::Functions(parameters)) => (*parameters, true),
+        None => (parameters.analysis, false),
+    };
+    let config = Config::from_parameters(parameters);
 
     let binary = file::read_file(&config);
+    // A memory map replaces the container, its file offsets refer to the file as it is
+    let (config, binary) = match &config.memory_map {
+        Some(memory_map) => {
+            let image = memory_map.image(&binary).unwrap_or_else(|error| {
+                clap::Error::raw(clap::error::ErrorKind::ValueValidation, error + "\n").exit()
+            });
+            (config, image)
+        }
+        None => file::apply_container(config, binary),
+    };
 
-    let mut top_candidates: MinHeap = Default::default();
-
-    // Synchronous
-    if !config.parallell {
-        for (binary_slice, endiannes, addressing_mode) in
-            iter_potential_instruction_configuration(&binary, &config)
-        {
-            analyse_instructions(
-                binary_slice,
-                &config,
-                endiannes,
-                addressing_mode,
-                &top_candidates,
+    // The detected regions replace the analysed range as a memory map
+    let mut code_regions = Vec::new();
+    let (config, binary) = if config.detect_code_regions {
+        // The histograms need the instruction length, which a container may have filled in by now
+        if config.infer_instr_len {
+            clap::Error::raw(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--detect-code-regions requires the instruction length -i\n",
             )
+            .exit()
         }
+        let regions = detect_code_regions(&binary, &config);
+        let Some(memory_map) = code_region_map(&regions, &config) else {
+            eprintln!("no code regions found");
+            std::process::exit(2)
+        };
+        code_regions = regions
+            .iter()
+            .zip(memory_map.code_regions())
+            .map(|(region, mapped)| CodeRegionReport::new(region, mapped.address))
+            .collect();
+        let image = memory_map
+            .image(&binary)
+            .expect("code regions are within the binary");
+        let config = Config {
+            file_offset: None,
+            memory_map: Some(memory_map),
+            ..config
+        };
+        (config, image)
+    } else {
+        (config, binary)
+    };
+
+    let analysis = Analysis::from_config(config);
+    let config = analysis.config();
+
+    // Call sites are only found when they are printed
+    let nr_call_sites = match (config.output_format, config.include_instructions) {
+        (_, true) => usize::MAX,
+        (OutputFormat::Text, false) => 0,
+        _ => SAMPLE_CALL_SITES,
+    };
+    // `config` must be the one the candidate was found with
+    let call_sites = |config: &Config, candidate: &Candidate| match nr_call_sites {
+        0 => Vec::new(),
+        nr_call_sites => Analysis::from_config(config.clone())
+            .call_edges(&binary, candidate)
+            .into_iter()
+            .take(nr_call_sites)
+            .collect(),
+    };
+
+    // Each candidate with the config it was found with, its report is without call sites until they are found below
+    let (mode, found): (Mode, Vec<(Config, Candidate, CandidateReport)>) =
+        if config.infer_length_rule {
+            let found = analysis
+                .infer_length_rule(&binary)
+                .into_iter()
+                .map(|(length_rule, candidate)| {
+                    let report = CandidateReport {
+                        length_rule: Some(length_rule.to_string()),
+                        ..CandidateReport::new(&candidate, Vec::new())
+                    };
+                    let config = Config {
+                        length_rule: Some(length_rule),
+                        ..config.clone()
+                    };
+                    (config, candidate, report)
+                })
+                .collect();
+            (Mode::LengthRules, found)
+        } else if config.infer_instr_len {
+            // The top few lengths are analysed, the detection alone often ranks a divisor or multiple of the real
+            // length close to it
+            let lengths = analysis
+                .infer_instr_len(&binary, 3)
+                .unwrap_or_else(|error| {
+                    eprintln!("{}", error);
+                    std::process::exit(2)
+                });
+            let found = lengths
+                .into_iter()
+                .map(|(instr_len, candidate)| {
+                    let report = CandidateReport {
+                        instr_len: Some(instr_len),
+                        ..CandidateReport::new(&candidate, Vec::new())
+                    };
+                    // The length was analysed, thus its config is valid
+                    (config.with_instr_len(instr_len).unwrap(), candidate, report)
+                })
+                .collect();
+            (Mode::InstructionLengths, found)
+        } else if config.infer_pc_offset {
+            let found = analysis
+                .infer_pc_offset(&binary)
+                .into_iter()
+                .map(|(pc_offset, votes, candidate)| {
+                    let report = CandidateReport {
+                        pc_offset: Some(pc_offset),
+                        votes: Some(votes),
+                        ..CandidateReport::new(&candidate, Vec::new())
+                    };
+                    let config = Config {
+                        pc_offset: Some(pc_offset),
+                        ..config.clone()
+                    };
+                    (config, candidate, report)
+                })
+                .collect();
+            (Mode::PcOffsets, found)
+        } else {
+            let found = analysis
+                .run(&binary)
+                .into_iter()
+                .map(|candidate| {
+                    let report = CandidateReport::new(&candidate, Vec::new());
+                    (config.clone(), candidate, report)
+                })
+                .collect();
+            (Mode::Results, found)
+        };
+
+    // We now have the top candidates, the call graph is made from the calls of the best one
+    if let Some(path) = &config.call_graph {
+        let Some((config, candidate, _)) = found.first() else {
+            eprintln!("no candidate to make the call graph of");
+            std::process::exit(2)
+        };
+        let call_graph = Analysis::from_config(config.clone()).call_graph(&binary, candidate);
+        let graph = match config.call_graph_format {
+            GraphFormat::Dot => call_graph.to_dot(),
+            GraphFormat::Graphml => call_graph.to_graphml(),
+        };
+        std::fs::write(path, graph).unwrap_or_else(|error| {
+            eprintln!("could not write call graph {}: {}", path.display(), error);
+            std::process::exit(2)
+        });
     }
-    // Parralell, speedup ~ min(num_cores, instr_byte_len), i.e given 32 bit instr and modern pc => 4x speedup
-    else {
-        iter_potential_instruction_configuration(&binary, &config)
-            .collect::<Vec<_>>()
-            .par_iter()
-            .for_each(|(binary_slice, endiannes, addressing_mode)| {
-                analyse_instructions(
-                    binary_slice,
-                    &config,
-                    endiannes,
-                    addressing_mode,
-                    &top_candidates,
-                )
-            });
+
+    if recover_functions {
+        let Some((config, candidate, _)) = found.first() else {
+            eprintln!("no candidate to recover the functions of");
+            std::process::exit(2)
+        };
+        let function_table = Analysis::from_config(config.clone()).functions(&binary, candidate);
+        print_functions(candidate, &function_table);
+        return;
     }
 
-    // We now have the top candidates, we can create a call graph, print the top candidates etc etc.
-    println!("RESULTS:");
-    for candidate in top_candidates.get_result() {
-        println!(
+    let candidates = found
+        .into_iter()
+        .map(|(config, candidate, report)| CandidateReport {
+            call_sites: call_sites(&config, &candidate)
+                .into_iter()
+                .map(|(call_site, target)| CallSiteReport { call_site, target })
+                .collect(),
+            ..report
+        })
+        .collect();
+
+    let report = Report::new(mode, code_regions, candidates);
+    match config.output_format {
+        OutputFormat::Text => print_text(&report),
+        OutputFormat::Json => println!("{}", report.to_json()),
+        OutputFormat::JsonLines => print!("{}", report.to_json_lines()),
+    }
+}
+
+fn print_text(report: &Report) {
+    if !report.code_regions.is_empty() {
+        println!("CODE REGIONS:");
+        for region in report.code_regions.iter() {
+            println!(
+                "Start: {:#x}\tEnd: {:#x}\tAddress: {:#x}\tEntropy: {:.2}\tSimilarity: {:.4}",
+                region.file_offset[0],
+                region.file_offset[1],
+                region.address,
+                region.entropy,
+                region.similarity
+            )
+        }
+    }
+
+    println!(
+        "{}",
+        match report.mode {
+            Mode::Results => "RESULTS:",
+            Mode::LengthRules => "LENGTH RULES:",
+            Mode::InstructionLengths => "INSTRUCTION LENGTHS:",
+            Mode::PcOffsets => "PC OFFSETS:",
+        }
+    );
+    for candidate in report.candidates.iter() {
+        print!(
             "Prob: {:.4} \tCall: {:#08x}\tRet: {:#08x}",
             candidate.probability, candidate.call_opcode, candidate.ret_opcode
+        );
+        if let Some(length_rule) = &candidate.length_rule {
+            print!("\tRule: {}", length_rule);
+        }
+        if let Some(instr_len) = candidate.instr_len {
+            print!("\tLen: {}", instr_len);
+        }
+        if let (Some(votes), Some(pc_offset)) = (candidate.votes, candidate.pc_offset) {
+            print!("\tVotes: {}\tPC offset: {:#x}", votes, pc_offset);
+        }
+        println!();
+
+        // The hypothesis that produced the candidate and the counts its probability is computed from
+        let configuration = &candidate.configuration;
+        println!(
+            "\tEndiannes: {}\tAddressing: {}\tOffset: {}\tOpcode: {} bits\tOperand: {}\tShift: {}\tBias: {}",
+            configuration.endiannes,
+            configuration.addressing_mode,
+            configuration.byte_offset,
+            configuration.call_opcode_len,
+            configuration.call_operand_bits,
+            configuration.left_shift_call_operand,
+            configuration.pc_bias
+        );
+        let counts = &candidate.counts;
+        println!(
+            "\tCalls: {}\tRets: {}\tPotential edges: {}\tValid edges: {}",
+            counts.calls, counts.rets, counts.potential_edges, counts.valid_edges
+        );
+
+        for call_site in candidate.call_sites.iter() {
+            println!("\t{:#x} -> {:#x}", call_site.call_site, call_site.target)
+        }
+    }
+}
+
+fn print_functions(candidate: &Candidate, function_table: &FunctionTable) {
+    println!(
+        "FUNCTIONS:\nCall: {:#08x}\tRet: {:#08x}",
+        candidate.call_opcode, candidate.ret_opcode
+    );
+    for function in function_table.functions.iter() {
+        println!(
+            "Start: {:#x}\tEnd: {:#x}\tSize: {}\tReturns: {}\tCallers: {}\tCallees: {}",
+            function.start,
+            function.end,
+            function.size(),
+            function.returns,
+            function.callers,
+            function.callees
         )
     }
+    println!(
+        "Functions: {}\tCoverage: {:.4}\tCovered: {}\tCode: {}",
+        function_table.functions.len(),
+        function_table.coverage(),
+        function_table.covered_len,
+        function_table.code_len
+    );
 }
 
-// TOdo move this to "analyse_binary.rs"
-fn analyse_instructions(
-    binary_slice: &[u8],
-    config: &Config,
-    endiannes: &Endiannes,
-    addressing_mode: &AddressingMode,
-    top_candidates: &MinHeap,
-) {
-    // We assume call instruction is among call candidates, and ret instruction for ret_candidates
-    let call_cand = call_candidates(binary_slice, config, endiannes);
-    let ret_cand = ret_candidates(binary_slice, config, endiannes);
-
-    for &(call_candidate, call_count) in call_cand.iter() {
-        // Valid addresses for instructions of the given call candidates
-        let potential_edges = find_potential_edges(
-            binary_slice,
-            call_candidate,
-            config,
-            endiannes,
-            addressing_mode,
-        );
+fn basefind(parameters: &BasefindParameters) {
+    let &BasefindParameters {
+        ref file_path,
+        ref endiannes,
+        pointer_len,
+        min_string_len,
+        align,
+        nr_cand,
+    } = parameters;
 
-        for &(ret_candidate, _ret_count) in ret_cand.iter() {
-            // valid addresses where there is a return preceding it
-            let valid_edges = filter_valid_edges(
-                binary_slice,
-                ret_candidate,
-                config,
-                &potential_edges,
-                endiannes,
-            );
-
-            // Calculate probability stuff
-            let ratio_valid: f64 = valid_edges.len() as f64 / call_count as f64;
-            let ratio_potential = potential_edges.len() as f64 / call_count as f64;
-            let probability = ((2.0 * ratio_valid) + ratio_potential) / 3.0;
-            //  use itertools::Itertools;
-            //  let ret_hits = valid_edges.iter().map(|(_, to)| to).unique().count();
-
-            // Add to heap if high probability
-            top_candidates.add_maybe(
-                config.nr_cand,
-                Candidate {
-                    probability,
-                    call_opcode: call_candidate,
-                    ret_opcode: ret_candidate,
-                },
-            )
-        }
+    if pointer_len % 8 != 0 || !(8..=64).contains(&pointer_len) || align == 0 {
+        clap::Error::raw(
+            clap::error::ErrorKind::ValueValidation,
+            format!(
+                "pointer length must be a multiple of 8 up to 64 and the alignment positive, got {} and {:#x}\n",
+                pointer_len, align
+            ),
+        )
+        .exit()
+    }
+
+    let binary = std::fs::read(file_path).expect("file not found");
+
+    println!("BASE ADDRESSES:");
+    for (base, votes, endiannes) in find_base_address(
+        &binary,
+        endiannes,
+        pointer_len,
+        min_string_len,
+        align,
+        nr_cand,
+    ) {
+        println!(
+            "Votes: {}\tBase: {:#x}\tEndiannes: {}",
+            votes,
+            base,
+            endiannes.to_possible_value().unwrap().get_name()
+        )
     }
 }
 
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::str::FromStr;
+
+use clap_num::maybe_hex;
+
+// Regions start in the image at the same offset modulo this as their address, thus instructions aligned to their
+// address are aligned the same way in every region. Covers instructions of up to 64 bits.
+const IMAGE_ALIGN: u64 = 16;
+
+/// Regions of a file and the addresses they are loaded at, i.e a bootloader, an application and overlays. The code
+/// regions are analysed together as a single image, see `MemoryMap::image`, and calls are resolved across them.
+#[derive(Clone, Debug)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    // Offset in the image of each code region, in the order of `code_regions`
+    image_offsets: Vec<usize>,
+}
+
+/// `file_offset` is the exclusive range of the file that is loaded at `address`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub file_offset: [usize; 2],
+    pub address: u64,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Region {
+    pub(crate) fn len(&self) -> usize {
+        self.file_offset[1] - self.file_offset[0]
+    }
+}
+
+impl MemoryMap {
+    /// Fails if a region is empty or the addresses of two regions overlap
+    pub fn new(mut regions: Vec<Region>) -> Result<MemoryMap, String> {
+        regions.sort_by_key(|region| region.address);
+        for region in regions.iter() {
+            if region.file_offset[0] >= region.file_offset[1] {
+                return Err(format!("memory map region {} is empty", region));
+            }
+        }
+        for (region, next) in regions.iter().zip(regions.iter().skip(1)) {
+            if region.address.saturating_add(region.len() as u64) > next.address {
+                return Err(format!(
+                    "memory map regions {} and {} overlap",
+                    region, next
+                ));
+            }
+        }
+
+        let mut image_offsets = Vec::new();
+        let mut image_len = 0;
+        for region in regions.iter().filter(|region| region.execute) {
+            let padding = (region.address as usize).wrapping_sub(image_len) % IMAGE_ALIGN as usize;
+            image_offsets.push(image_len + padding);
+            image_len += padding + region.len();
+        }
+
+        if image_offsets.is_empty() {
+            return Err("memory map has no executable region".to_string());
+        }
+        Ok(MemoryMap {
+            regions,
+            image_offsets,
+        })
+    }
+
+    /// Executable regions in increasing address order
+    pub fn code_regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(|region| region.execute)
+    }
+
+    /// The code regions of the file in increasing address order, each padded with zeros to start at the same
+    /// offset modulo 16 as its address. Fails if a region is outside the file.
+    pub fn image(&self, binary: &[u8]) -> Result<Vec<u8>, String> {
+        let mut image = Vec::new();
+        for (region, &image_offset) in self.code_regions().zip(self.image_offsets.iter()) {
+            let data = binary
+                .get(region.file_offset[0]..region.file_offset[1])
+                .ok_or_else(|| {
+                    format!(
+                        "memory map region {} is outside the file of {:#x} bytes",
+                        region,
+                        binary.len()
+                    )
+                })?;
+            image.resize(image_offset, 0);
+            image.extend_from_slice(data);
+        }
+        Ok(image)
+    }
+
+    /// Address of the byte at the offset in the image, None for the padding between regions
+    pub fn address(&self, image_offset: usize) -> Option<u64> {
+        let index = self
+            .image_offsets
+            .partition_point(|&offset| offset <= image_offset)
+            .checked_sub(1)?;
+        let region = self.code_regions().nth(index)?;
+        let offset = image_offset - self.image_offsets[index];
+        (offset < region.len()).then(|| region.address + offset as u64)
+    }
+
+    /// Offset in the file of the byte at the offset in the image, None for the padding between regions
+    pub fn file_offset(&self, image_offset: usize) -> Option<usize> {
+        let address = self.address(image_offset)?;
+        self.code_regions()
+            .find(|region| {
+                (region.address..region.address + region.len() as u64).contains(&address)
+            })
+            .map(|region| region.file_offset[0] + (address - region.address) as usize)
+    }
+
+    /// Offset in the image of the byte loaded at the address, None if no code region holds it
+    pub fn image_offset(&self, address: u64) -> Option<usize> {
+        self.code_regions()
+            .zip(self.image_offsets.iter())
+            .find(|(region, _)| {
+                (region.address..region.address + region.len() as u64).contains(&address)
+            })
+            .map(|(region, &image_offset)| image_offset + (address - region.address) as usize)
+    }
+}
+
+impl FromStr for MemoryMap {
+    type Err = String;
+
+    /// Parses one region per line as `START END ADDRESS PERMISSIONS`, i.e `0x0 0x4000 0x08000000 r-x`, where
+    /// START and END are the exclusive range in the file and PERMISSIONS is any of `r`, `w` and `x` or `-`.
+    /// Everything after a `#` is a comment.
+    fn from_str(text: &str) -> Result<MemoryMap, String> {
+        let mut regions = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = || {
+                format!(
+                    "invalid memory map line {} '{}', expected START END ADDRESS PERMISSIONS",
+                    index + 1,
+                    line
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [start, end, address, permissions] = fields[..] else {
+                return Err(error());
+            };
+            if !permissions.chars().all(|c| "rwx-".contains(c)) {
+                return Err(error());
+            }
+
+            regions.push(Region {
+                file_offset: [
+                    maybe_hex::<usize>(start).map_err(|_| error())?,
+                    maybe_hex::<usize>(end).map_err(|_| error())?,
+                ],
+                address: maybe_hex::<u64>(address).map_err(|_| error())?,
+                read: permissions.contains('r'),
+                write: permissions.contains('w'),
+                execute: permissions.contains('x'),
+            });
+        }
+        MemoryMap::new(regions)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..{:#x} at {:#x}",
+            self.file_offset[0], self.file_offset[1], self.address
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A bootloader and an application that starts 4 bytes into its 16 byte line, and data that is not analysed
+    const MAP: &str = "
+        # bootloader
+        0x0 0x10 0x1000 r-x
+        0x20 0x2a 0x2004 r-x # application
+        0x40 0x50 0x3000 rw-
+    ";
+
+    #[test]
+    fn regions_are_parsed() {
+        let memory_map: MemoryMap = MAP.parse().unwrap();
+        assert_eq!(memory_map.regions.len(), 3);
+        assert_eq!(
+            memory_map.regions[2],
+            Region {
+                file_offset: [0x40, 0x50],
+                address: 0x3000,
+                read: true,
+                write: true,
+                execute: false,
+            }
+        );
+        assert_eq!(memory_map.code_regions().count(), 2);
+
+        assert!("0x0 0x10 0x1000".parse::<MemoryMap>().is_err());
+        assert!("0x0 0x10 0x1000 r-y".parse::<MemoryMap>().is_err());
+        assert!("0x0 0x10 0x1000 --x\n0x10 0x20 0x1008 --x"
+            .parse::<MemoryMap>()
+            .is_err());
+        assert!("0x0 0x10 0x1000 r--".parse::<MemoryMap>().is_err());
+    }
+
+    #[test]
+    fn image_pads_regions_to_their_alignment() {
+        let memory_map: MemoryMap = MAP.parse().unwrap();
+        let binary: Vec<u8> = (1..=0x50).collect();
+        let image = memory_map.image(&binary).unwrap();
+        assert_eq!(image.len(), 0x1e);
+        assert_eq!(image[..0x10], binary[..0x10]);
+        assert_eq!(image[0x10..0x14], [0; 4]);
+        assert_eq!(image[0x14..], binary[0x20..0x2a]);
+
+        assert!(memory_map.image(&binary[..0x28]).is_err());
+    }
+
+    #[test]
+    fn addresses_round_trip_through_the_image() {
+        let memory_map: MemoryMap = MAP.parse().unwrap();
+        assert_eq!(memory_map.address(0), Some(0x1000));
+        assert_eq!(memory_map.address(0x14), Some(0x2004));
+        assert_eq!(memory_map.file_offset(0x14), Some(0x20));
+        // The padding and the end of the image
+        for image_offset in [0x10, 0x13, 0x1e] {
+            assert_eq!(memory_map.address(image_offset), None);
+            assert_eq!(memory_map.file_offset(image_offset), None);
+        }
+
+        for image_offset in (0..0x10).chain(0x14..0x1e) {
+            let address = memory_map.address(image_offset).unwrap();
+            assert_eq!(memory_map.image_offset(address), Some(image_offset));
+        }
+        // Data and the gap between the code regions
+        assert_eq!(memory_map.image_offset(0x3000), None);
+        assert_eq!(memory_map.image_offset(0x2000), None);
+        assert_eq!(memory_map.image_offset(0x1010), None);
+    }
+}
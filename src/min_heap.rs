@@ -1,18 +1,37 @@
 use std::cmp::Ordering;
-use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::sync::{Arc, Mutex};
 
-#[derive(Copy, Clone)]
+use crate::iter_instructions::InstructionConfiguration;
+use crate::prelude::*;
+
+#[derive(Clone)]
 pub struct Candidate {
     pub probability: f64,
     pub call_opcode: u64,
     pub ret_opcode: u64,
+    pub call_operand_layout: OperandLayout,
+    // Differs between candidates when the call opcode length is searched for
+    pub call_opcode_mask: u64,
+    pub configuration: InstructionConfiguration,
+    // Counts the probability is computed from, of the call and ret opcodes and of the edges before and after
+    // `filter_valid_edges`
+    pub call_count: usize,
+    pub ret_count: usize,
+    pub potential_edges: usize,
+    pub valid_edges: usize,
+}
+
+impl Candidate {
+    /// Number of bits in the call opcode
+    pub fn call_opcode_len(&self) -> u32 {
+        self.call_opcode_mask.count_ones()
+    }
 }
 
 impl PartialEq for Candidate {
     fn eq(&self, other: &Self) -> bool {
-        self.probability == other.probability
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -26,9 +45,12 @@ impl PartialOrd for Candidate {
 
 impl Ord for Candidate {
     fn cmp(&self, other: &Self) -> Ordering {
-        Reverse(self.probability)
-            .partial_cmp(&Reverse(other.probability))
-            .unwrap()
+        // Ties go to the shorter call opcode, longer ones often only add operand bits that happen to be constant.
+        // Compared as a total order so a NaN from a `Scorer` can not panic, `analyse_instructions` scores it as 0
+        other
+            .probability
+            .total_cmp(&self.probability)
+            .then_with(|| self.call_opcode_len().cmp(&other.call_opcode_len()))
     }
 }
 
@@ -0,0 +1,233 @@
+use itertools::Itertools;
+use rustc_hash::FxHashSet;
+use std::fmt;
+
+/// Describes which instruction bits make up the call operand. The operand is assembled by concatenating the
+/// inclusive `[high, low]` bit ranges in order, the first range being the most significant part, i.e RISC-V JAL
+/// is `31,19:12,20,30:21`. Sign extension and left shifting is done on the assembled operand.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct OperandLayout {
+    ranges: Vec<[u64; 2]>,
+    len: u64,
+}
+
+impl OperandLayout {
+    pub fn new(instr_len: u64, ranges: Vec<[u64; 2]>) -> Result<OperandLayout, String> {
+        if ranges.is_empty() {
+            return Err("call operand needs at least one bit range".to_string());
+        }
+
+        let ranges: Vec<[u64; 2]> = ranges
+            .into_iter()
+            .map(|[a, b]| [a.max(b), a.min(b)])
+            .collect();
+
+        let mut mask = 0;
+        for &[high, low] in ranges.iter() {
+            if high >= instr_len {
+                return Err(format!(
+                    "call operand bit {} is out of range for instruction length {}",
+                    high, instr_len
+                ));
+            }
+            let range_mask = range_mask(high, low);
+            if mask & range_mask != 0 {
+                return Err(format!("call operand bit range {}:{} overlaps", high, low));
+            }
+            mask |= range_mask;
+        }
+
+        Ok(OperandLayout {
+            len: mask.count_ones() as u64,
+            ranges: merge_adjacent(ranges),
+        })
+    }
+
+    /// Layout of a single contiguous field given by a mask
+    pub fn contiguous(mask: u64) -> OperandLayout {
+        let high = (u64::BITS - 1 - mask.leading_zeros()) as u64;
+        let low = mask.trailing_zeros() as u64;
+        OperandLayout {
+            ranges: vec![[high, low]],
+            len: high - low + 1,
+        }
+    }
+
+    pub fn ranges(&self) -> &[[u64; 2]] {
+        &self.ranges
+    }
+
+    /// Number of bits in the assembled operand
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// All instruction bits that are part of the operand
+    pub fn mask(&self) -> u64 {
+        self.ranges
+            .iter()
+            .fold(0, |mask, &[high, low]| mask | range_mask(high, low))
+    }
+
+    #[inline]
+    pub fn extract(&self, instr: u64) -> u64 {
+        // Fast path, by far the most common case
+        if let [[high, low]] = self.ranges[..] {
+            return (instr & range_mask(high, low)) >> low;
+        }
+        self.ranges.iter().fold(0, |operand, &[high, low]| {
+            ((operand << (high - low)) << 1) | ((instr & range_mask(high, low)) >> low)
+        })
+    }
+
+    #[inline]
+    pub fn extract_signed(&self, instr: u64) -> i64 {
+        let unused = u64::BITS as u64 - self.len;
+        ((self.extract(instr) << unused) as i64) >> unused
+    }
+
+    /// Layouts made by splitting the operand bits of this layout into at most `max_segments` contiguous segments
+    /// and reordering them. Includes this layout itself.
+    pub fn search_space(&self, max_segments: usize) -> Vec<OperandLayout> {
+        // Instruction bit indexes in operand order, most significant first
+        let bits: Vec<u64> = self
+            .ranges
+            .iter()
+            .flat_map(|&[high, low]| (low..=high).rev())
+            .collect();
+
+        let mut layouts: FxHashSet<OperandLayout> = Default::default();
+        layouts.insert(self.clone());
+
+        for nr_segments in 2..=max_segments.min(bits.len()) {
+            for splits in (1..bits.len()).combinations(nr_segments - 1) {
+                let segments: Vec<&[u64]> = std::iter::once(0)
+                    .chain(splits.iter().copied())
+                    .zip(splits.iter().copied().chain(std::iter::once(bits.len())))
+                    .map(|(start, end)| &bits[start..end])
+                    .collect();
+
+                for order in segments.iter().permutations(nr_segments) {
+                    let ranges = order
+                        .iter()
+                        .flat_map(|segment| segment_ranges(segment))
+                        .collect();
+                    layouts.insert(OperandLayout {
+                        ranges: merge_adjacent(ranges),
+                        len: self.len,
+                    });
+                }
+            }
+        }
+
+        layouts.into_iter().collect()
+    }
+}
+
+impl fmt::Display for OperandLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|&[high, low]| {
+                if high == low {
+                    format!("{}", high)
+                } else {
+                    format!("{}:{}", high, low)
+                }
+            })
+            .join(",");
+        write!(f, "{}", ranges)
+    }
+}
+
+/// Parses bit ranges on the form `31,19:12,20,30:21`
+pub fn parse_bit_ranges(value: &str) -> Result<Vec<[u64; 2]>, String> {
+    value
+        .split(',')
+        .map(|range| {
+            let parse = |bit: &str| {
+                bit.trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid bit range '{}'", range))
+            };
+            match range.split_once(':') {
+                Some((high, low)) => Ok([parse(high)?, parse(low)?]),
+                None => parse(range).map(|bit| [bit, bit]),
+            }
+        })
+        .collect()
+}
+
+#[inline]
+fn range_mask(high: u64, low: u64) -> u64 {
+    (u64::MAX >> (u64::BITS as u64 - 1 - high)) & (u64::MAX << low)
+}
+
+// Ranges of consecutive, descending instruction bits in a segment
+fn segment_ranges(segment: &[u64]) -> Vec<[u64; 2]> {
+    merge_adjacent(segment.iter().map(|&bit| [bit, bit]).collect())
+}
+
+// 12:8 followed by 7:4 is the same as 12:4
+fn merge_adjacent(ranges: Vec<[u64; 2]>) -> Vec<[u64; 2]> {
+    let mut merged: Vec<[u64; 2]> = Vec::with_capacity(ranges.len());
+    for [high, low] in ranges {
+        match merged.last_mut() {
+            Some(last) if last[1] == high + 1 => last[1] = low,
+            _ => merged.push([high, low]),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAL: &str = "31,19:12,20,30:21";
+
+    // RISC-V JAL with ra as the link register, the offset is in bytes and always even
+    fn jal(offset: i64) -> u64 {
+        let imm = offset as u64;
+        ((imm >> 20 & 1) << 31)
+            | ((imm >> 1 & 0x3ff) << 21)
+            | ((imm >> 11 & 1) << 20)
+            | ((imm >> 12 & 0xff) << 12)
+            | 0xef
+    }
+
+    #[test]
+    fn jal_offsets_round_trip() {
+        let layout = OperandLayout::new(32, parse_bit_ranges(JAL).unwrap()).unwrap();
+        assert_eq!(layout.len(), 20);
+        assert_eq!(layout.mask(), 0xfffff000);
+        for offset in [0, 2, 0x7fe, 0x800, 0x1000, 0xffffe, -2, -0x800, -0x100000] {
+            // The operand is the offset shifted right by one, see `--left-shift-call-operand`
+            assert_eq!(layout.extract_signed(jal(offset)) << 1, offset);
+        }
+    }
+
+    #[test]
+    fn jal_is_in_the_search_space_of_four_segments() {
+        let contiguous = OperandLayout::contiguous(0xfffff000);
+        let jal = OperandLayout::new(32, parse_bit_ranges(JAL).unwrap()).unwrap();
+        assert!(!contiguous.search_space(3).contains(&jal));
+        assert!(contiguous.search_space(4).contains(&jal));
+    }
+
+    #[test]
+    fn ranges_are_validated_and_merged() {
+        assert!(OperandLayout::new(32, Vec::new()).is_err());
+        assert!(OperandLayout::new(32, vec![[32, 20]]).is_err());
+        assert!(OperandLayout::new(32, vec![[31, 20], [25, 12]]).is_err());
+        let layout = OperandLayout::new(32, vec![[12, 8], [7, 4]]).unwrap();
+        assert_eq!(layout.ranges(), &[[12, 4]]);
+        assert_eq!(layout.to_string(), "12:4");
+        assert_eq!(layout, OperandLayout::contiguous(0x1ff0));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::file::{read_word, Container, Section};
+use crate::prelude::*;
+
+const IMAGE_FILE_MACHINE_POWERPCBE: u64 = 0x1f2;
+const IMAGE_SCN_CNT_CODE: u64 = 0x20;
+const IMAGE_SCN_MEM_EXECUTE: u64 = 0x20000000;
+
+/// Reads the PE header, whatever the machine (i.e Windows CE on SH or MIPS). Code is taken from the executable
+/// sections. None if the binary is not a PE or the headers are truncated.
+pub fn parse_pe(binary: &[u8]) -> Option<Container> {
+    if !binary.starts_with(b"MZ") {
+        return None;
+    }
+    // The headers are always little endian
+    let read = |offset: usize, len: usize| read_word(binary, &Endiannes::Little, offset, len);
+
+    let signature = read(0x3c, 4)? as usize;
+    if binary.get(signature..signature + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff_header = signature + 4;
+    let machine = read(coff_header, 2)?;
+    let number_of_sections = read(coff_header + 2, 2)? as usize;
+    let size_of_optional_header = read(coff_header + 16, 2)? as usize;
+
+    let optional_header = coff_header + 20;
+    let (word_len, image_base) = match read(optional_header, 2)? {
+        0x10b => (32, read(optional_header + 28, 4)?),
+        0x20b => (64, read(optional_header + 24, 8)?),
+        _ => return None,
+    };
+
+    let mut code = Vec::new();
+    let mut segments = Vec::new();
+    let section_table = optional_header + size_of_optional_header;
+    for index in 0..number_of_sections {
+        let header = section_table + index * 40;
+        if header > binary.len() {
+            return None;
+        }
+        let virtual_size = read(header + 8, 4)?;
+        let virtual_address = read(header + 12, 4)?;
+        let size_of_raw_data = read(header + 16, 4)?;
+        let pointer_to_raw_data = read(header + 20, 4)?;
+        let characteristics = read(header + 36, 4)?;
+
+        // The raw data is padded to the file alignment, the virtual size is the actual size when given
+        let size = match virtual_size {
+            0 => size_of_raw_data,
+            _ => virtual_size.min(size_of_raw_data),
+        };
+        if size == 0 {
+            continue;
+        }
+        let section = Section {
+            file_offset: [
+                pointer_to_raw_data as usize,
+                (pointer_to_raw_data + size) as usize,
+            ],
+            address: image_base.checked_add(virtual_address)?,
+        };
+        if section.file_offset[1] > binary.len() {
+            return None;
+        }
+        if characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0 {
+            code.push(section);
+        }
+        segments.push(section);
+    }
+
+    Some(Container {
+        endiannes: if machine == IMAGE_FILE_MACHINE_POWERPCBE {
+            Endiannes::Big
+        } else {
+            Endiannes::Little
+        },
+        word_len: Some(word_len),
+        code,
+        segments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::tests::write_word;
+
+    // PE with a single code section of 0x20 bytes at file offset 0x100 and relative address 0x1000
+    fn pe(magic: u64, image_base: u64) -> Vec<u8> {
+        let mut binary = vec![0; 0x120];
+        binary[..2].copy_from_slice(b"MZ");
+        binary[0x40..0x44].copy_from_slice(b"PE\0\0");
+        let mut write = |offset: usize, len: usize, value: u64| {
+            write_word(&mut binary, &Endiannes::Little, offset, len, value)
+        };
+        write(0x3c, 4, 0x40);
+        // COFF header: i386, one section, 0x70 bytes of optional header
+        write(0x44, 2, 0x14c);
+        write(0x44 + 2, 2, 1);
+        write(0x44 + 16, 2, 0x70);
+        write(0x58, 2, magic);
+        match magic {
+            0x10b => write(0x58 + 28, 4, image_base),
+            _ => write(0x58 + 24, 8, image_base),
+        }
+        let section = 0x58 + 0x70;
+        write(section + 8, 4, 0x20);
+        write(section + 12, 4, 0x1000);
+        write(section + 16, 4, 0x20);
+        write(section + 20, 4, 0x100);
+        write(section + 36, 4, IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE);
+        binary
+    }
+
+    #[test]
+    fn code_section_is_at_image_base() {
+        let container = parse_pe(&pe(0x10b, 0x400000)).unwrap();
+        assert!(matches!(container.endiannes, Endiannes::Little));
+        assert_eq!(container.word_len, Some(32));
+        let code = container.code_range().unwrap();
+        assert_eq!((code.file_offset, code.address), ([0x100, 0x120], 0x401000));
+
+        let container = parse_pe(&pe(0x20b, 0x140000000)).unwrap();
+        assert_eq!(container.word_len, Some(64));
+        assert_eq!(container.code_range().unwrap().address, 0x140001000);
+    }
+
+    #[test]
+    fn broken_headers_are_rejected() {
+        assert!(parse_pe(&pe(0x10b, 0x400000)[..0xd0]).is_none());
+        assert!(parse_pe(&pe(0x10b, 0x400000)[..0x50]).is_none());
+        // The section address does not fit in 64 bits
+        assert!(parse_pe(&pe(0x20b, 0xfffffffffffff000)).is_none());
+    }
+}
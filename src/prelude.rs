@@ -1,6 +1,14 @@
 pub use crate::cli::addressing_mode::AddressingMode;
 pub use crate::cli::config::Config;
 pub use crate::cli::endiannes::Endiannes;
+pub use crate::cli::graph_format::GraphFormat;
+pub use crate::cli::output_format::OutputFormat;
+pub use crate::cli::scoring::Scoring;
+pub use crate::iter_instructions::InstructionConfiguration;
+pub use crate::length_rule::{LengthPattern, LengthRule};
+pub use crate::memory_map::MemoryMap;
+pub use crate::operand_layout::{parse_bit_ranges, OperandLayout};
+pub use crate::scorer::{Evidence, Scorer};
 pub use std::path::PathBuf;
 // TODO add INSTR_LEN if we do that
 
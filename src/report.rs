@@ -0,0 +1,387 @@
+//! Machine readable results for `--output-format json` and `json-lines`. A JSON document is a `Report`, JSON Lines
+//! output is a `header` line followed by a line per code region and per candidate, each with a `type` field. Fields
+//! are only ever added within a schema version, any other change raises `SCHEMA_VERSION`.
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "mode": "results" | "length_rules" | "instruction_lengths" | "pc_offsets",
+//!   "code_regions": [{ "file_offset": [start, end], "address", "entropy", "similarity" }],
+//!   "candidates": [{
+//!     "probability", "call_opcode", "ret_opcode", "call_opcode_mask",
+//!     "configuration": { "endiannes", "addressing_mode", "byte_offset", "call_opcode_len", "call_operand_bits",
+//!                        "left_shift_call_operand", "pc_bias" },
+//!     "counts": { "calls", "rets", "potential_edges", "valid_edges" },
+//!     "score": { "ratio_valid", "ratio_potential" },
+//!     "call_sites": [{ "call_site", "target" }],
+//!     "length_rule" | "instr_len" | "pc_offset" and "votes", depending on the mode
+//!   }]
+//! }
+//! ```
+//!
+//! The `score` ratios are the components of the default `EdgeScorer`. With `--scoring targets` the probability also
+//! depends on the distinct call targets, which are not reported, thus the ratios do not add up to it.
+//!
+//! Numbers are integers except for probabilities, entropies and ratios. Addresses are virtual addresses, see
+//! `AddressSpace`.
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::code_regions::CodeRegion;
+use crate::min_heap::Candidate;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+pub struct Report {
+    pub schema_version: u32,
+    pub mode: Mode,
+    pub code_regions: Vec<CodeRegionReport>,
+    pub candidates: Vec<CandidateReport>,
+}
+
+/// What the candidates were searched for, decides which of the mode specific fields of a candidate are present
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Results,
+    LengthRules,
+    InstructionLengths,
+    PcOffsets,
+}
+
+#[derive(Serialize)]
+pub struct CodeRegionReport {
+    pub file_offset: [usize; 2],
+    pub address: u64,
+    pub entropy: f64,
+    pub similarity: f64,
+}
+
+#[derive(Serialize)]
+pub struct CandidateReport {
+    pub probability: f64,
+    pub call_opcode: u64,
+    pub ret_opcode: u64,
+    pub call_opcode_mask: u64,
+    pub configuration: ConfigurationReport,
+    pub counts: CountsReport,
+    pub score: ScoreReport,
+    // All calls with --include-instructions, otherwise the first few
+    pub call_sites: Vec<CallSiteReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length_rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instr_len: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pc_offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub votes: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ConfigurationReport {
+    pub endiannes: String,
+    pub addressing_mode: String,
+    pub byte_offset: usize,
+    pub call_opcode_len: u32,
+    pub call_operand_bits: String,
+    pub left_shift_call_operand: u64,
+    pub pc_bias: i64,
+}
+
+#[derive(Serialize)]
+pub struct CountsReport {
+    pub calls: usize,
+    pub rets: usize,
+    pub potential_edges: usize,
+    pub valid_edges: usize,
+}
+
+/// Shares of the calls that are potential and valid edges, the default `EdgeScorer` computes the probability from
+/// them
+#[derive(Serialize)]
+pub struct ScoreReport {
+    pub ratio_valid: f64,
+    pub ratio_potential: f64,
+}
+
+#[derive(Serialize)]
+pub struct CallSiteReport {
+    pub call_site: u64,
+    pub target: u64,
+}
+
+// A line of JSON Lines output
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line<'a> {
+    Header { schema_version: u32, mode: Mode },
+    CodeRegion(&'a CodeRegionReport),
+    Candidate(&'a CandidateReport),
+}
+
+impl Report {
+    pub fn new(
+        mode: Mode,
+        code_regions: Vec<CodeRegionReport>,
+        candidates: Vec<CandidateReport>,
+    ) -> Report {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            mode,
+            code_regions,
+            candidates,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// One line per object, each ended by a newline
+    pub fn to_json_lines(&self) -> String {
+        let header = Line::Header {
+            schema_version: self.schema_version,
+            mode: self.mode,
+        };
+        std::iter::once(header)
+            .chain(self.code_regions.iter().map(Line::CodeRegion))
+            .chain(self.candidates.iter().map(Line::Candidate))
+            .map(|line| serde_json::to_string(&line).unwrap() + "\n")
+            .collect()
+    }
+}
+
+impl CodeRegionReport {
+    pub fn new(code_region: &CodeRegion, address: u64) -> CodeRegionReport {
+        CodeRegionReport {
+            file_offset: code_region.file_offset,
+            address,
+            entropy: code_region.entropy,
+            similarity: code_region.similarity,
+        }
+    }
+}
+
+impl CandidateReport {
+    /// Report without the mode specific fields, `call_sites` are (call site, target) addresses
+    pub fn new(candidate: &Candidate, call_sites: Vec<(u64, u64)>) -> CandidateReport {
+        let configuration = &candidate.configuration;
+        let ratio = |count: usize| match candidate.call_count {
+            0 => 0.0,
+            call_count => count as f64 / call_count as f64,
+        };
+
+        CandidateReport {
+            probability: candidate.probability,
+            call_opcode: candidate.call_opcode,
+            ret_opcode: candidate.ret_opcode,
+            call_opcode_mask: candidate.call_opcode_mask,
+            configuration: ConfigurationReport {
+                endiannes: value_name(&configuration.endiannes),
+                addressing_mode: value_name(&configuration.addressing_mode),
+                byte_offset: configuration.byte_offset,
+                call_opcode_len: candidate.call_opcode_len(),
+                call_operand_bits: candidate.call_operand_layout.to_string(),
+                left_shift_call_operand: configuration.left_shift_call_operand,
+                pc_bias: configuration.pc_bias,
+            },
+            counts: CountsReport {
+                calls: candidate.call_count,
+                rets: candidate.ret_count,
+                potential_edges: candidate.potential_edges,
+                valid_edges: candidate.valid_edges,
+            },
+            score: ScoreReport {
+                ratio_valid: ratio(candidate.valid_edges),
+                ratio_potential: ratio(candidate.potential_edges),
+            },
+            call_sites: call_sites
+                .into_iter()
+                .map(|(call_site, target)| CallSiteReport { call_site, target })
+                .collect(),
+            length_rule: None,
+            instr_len: None,
+            pc_offset: None,
+            votes: None,
+        }
+    }
+}
+
+// Name of the value on the command line, i.e "absolute-word"
+fn value_name(value: &impl ValueEnum) -> String {
+    value.to_possible_value().unwrap().get_name().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iter_instructions::InstructionConfiguration;
+    use crate::prelude::*;
+    use serde_json::Value;
+
+    fn report() -> Report {
+        let candidate = Candidate {
+            probability: 0.75,
+            call_opcode: 0x0c000000,
+            ret_opcode: 0x03e00008,
+            call_operand_layout: OperandLayout::contiguous(0x03ffffff),
+            call_opcode_mask: 0xfc000000,
+            configuration: InstructionConfiguration {
+                byte_offset: 0,
+                endiannes: Endiannes::Big,
+                addressing_mode: AddressingMode::AbsoluteWord,
+                pc_bias: 0,
+                left_shift_call_operand: 2,
+            },
+            call_count: 4,
+            ret_count: 3,
+            potential_edges: 3,
+            valid_edges: 2,
+        };
+        let code_region = CodeRegion {
+            file_offset: [0x1000, 0x3000],
+            entropy: 5.5,
+            similarity: 0.9,
+        };
+        Report::new(
+            Mode::LengthRules,
+            vec![CodeRegionReport::new(&code_region, 0x80001000)],
+            vec![CandidateReport {
+                length_rule: Some("0x3=0x3:32".to_string()),
+                ..CandidateReport::new(&candidate, vec![(0x80001000, 0x80002000)])
+            }],
+        )
+    }
+
+    // Field names and JSON types of an object, in alphabetical order
+    fn schema(value: &Value) -> Vec<(String, &'static str)> {
+        value
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| {
+                let value_type = match value {
+                    Value::Null => "null",
+                    Value::Bool(_) => "bool",
+                    Value::Number(number) if number.is_f64() => "float",
+                    Value::Number(_) => "integer",
+                    Value::String(_) => "string",
+                    Value::Array(_) => "array",
+                    Value::Object(_) => "object",
+                };
+                (name.clone(), value_type)
+            })
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &'static str)]) -> Vec<(String, &'static str)> {
+        let mut fields: Vec<_> = fields
+            .iter()
+            .map(|&(name, value_type)| (name.to_string(), value_type))
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn report_follows_schema_version_1() {
+        let json: Value = serde_json::from_str(&report().to_json()).unwrap();
+        assert_eq!(
+            schema(&json),
+            fields(&[
+                ("schema_version", "integer"),
+                ("mode", "string"),
+                ("code_regions", "array"),
+                ("candidates", "array"),
+            ])
+        );
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["mode"], "length_rules");
+        assert_eq!(
+            schema(&json["code_regions"][0]),
+            fields(&[
+                ("file_offset", "array"),
+                ("address", "integer"),
+                ("entropy", "float"),
+                ("similarity", "float"),
+            ])
+        );
+
+        let candidate = &json["candidates"][0];
+        assert_eq!(
+            schema(candidate),
+            fields(&[
+                ("probability", "float"),
+                ("call_opcode", "integer"),
+                ("ret_opcode", "integer"),
+                ("call_opcode_mask", "integer"),
+                ("configuration", "object"),
+                ("counts", "object"),
+                ("score", "object"),
+                ("call_sites", "array"),
+                ("length_rule", "string"),
+            ])
+        );
+        assert_eq!(
+            schema(&candidate["configuration"]),
+            fields(&[
+                ("endiannes", "string"),
+                ("addressing_mode", "string"),
+                ("byte_offset", "integer"),
+                ("call_opcode_len", "integer"),
+                ("call_operand_bits", "string"),
+                ("left_shift_call_operand", "integer"),
+                ("pc_bias", "integer"),
+            ])
+        );
+        assert_eq!(
+            candidate["configuration"]["addressing_mode"],
+            "absolute-word"
+        );
+        assert_eq!(candidate["configuration"]["call_operand_bits"], "25:0");
+        assert_eq!(
+            schema(&candidate["counts"]),
+            fields(&[
+                ("calls", "integer"),
+                ("rets", "integer"),
+                ("potential_edges", "integer"),
+                ("valid_edges", "integer"),
+            ])
+        );
+        assert_eq!(
+            schema(&candidate["score"]),
+            fields(&[("ratio_valid", "float"), ("ratio_potential", "float")])
+        );
+        assert_eq!(candidate["score"]["ratio_valid"], 0.5);
+        assert_eq!(
+            schema(&candidate["call_sites"][0]),
+            fields(&[("call_site", "integer"), ("target", "integer")])
+        );
+    }
+
+    #[test]
+    fn json_lines_start_with_a_header() {
+        let lines: Vec<Value> = report()
+            .to_json_lines()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let types: Vec<&str> = lines
+            .iter()
+            .map(|line| line["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, vec!["header", "code_region", "candidate"]);
+        assert_eq!(
+            schema(&lines[0]),
+            fields(&[
+                ("type", "string"),
+                ("schema_version", "integer"),
+                ("mode", "string"),
+            ])
+        );
+        assert_eq!(lines[2]["length_rule"], "0x3=0x3:32");
+    }
+}
@@ -0,0 +1,56 @@
+use itertools::Itertools;
+
+/// What is known about a call and ret opcode pair when it is scored by `analyse_instructions`. Edges are (call
+/// site, target) offsets in the slice, see `find_potential_edges` and `filter_valid_edges`.
+pub struct Evidence<'a> {
+    // Occurrences of the call and ret opcodes
+    pub call_count: usize,
+    pub ret_count: usize,
+    pub potential_edges: &'a [(usize, usize)],
+    pub valid_edges: &'a [(usize, usize)],
+}
+
+/// Scores a call and ret opcode pair, the candidates with the highest scores are kept as their probability.
+/// Scorers are shared by the threads of `--parallell`, and should return values in 0 to 1.
+pub trait Scorer: Send + Sync {
+    fn score(&self, evidence: &Evidence) -> f64;
+}
+
+/// The default, weighs the share of calls that have a preceding return twice the share that land in the binary
+pub struct EdgeScorer;
+
+/// Like `EdgeScorer`, but also rewards pairs where the returns are followed by many distinct call targets, since
+/// calls of real functions reach most of them while data that happens to decode as calls keeps hitting a few
+pub struct TargetScorer;
+
+impl Evidence<'_> {
+    pub fn ratio_valid(&self) -> f64 {
+        self.valid_edges.len() as f64 / self.call_count as f64
+    }
+
+    pub fn ratio_potential(&self) -> f64 {
+        self.potential_edges.len() as f64 / self.call_count as f64
+    }
+
+    /// Number of functions reached by the valid edges
+    pub fn distinct_targets(&self) -> usize {
+        self.valid_edges.iter().map(|&(_, to)| to).unique().count()
+    }
+}
+
+impl Scorer for EdgeScorer {
+    fn score(&self, evidence: &Evidence) -> f64 {
+        ((2.0 * evidence.ratio_valid()) + evidence.ratio_potential()) / 3.0
+    }
+}
+
+impl Scorer for TargetScorer {
+    fn score(&self, evidence: &Evidence) -> f64 {
+        // Every return precedes at most one function, more targets only come from a large `ret_func_dist`
+        let ratio_targets = match evidence.ret_count {
+            0 => 0.0,
+            ret_count => (evidence.distinct_targets() as f64 / ret_count as f64).min(1.0),
+        };
+        ((2.0 * evidence.ratio_valid()) + evidence.ratio_potential() + ratio_targets) / 4.0
+    }
+}